@@ -0,0 +1,47 @@
+// Encoder acceleration - detents turned quickly one after another count as several steps
+
+// ? a detent within `interval` ms of the last one counts as `multiplier` steps
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Threshold {
+    pub interval: u32,
+    pub multiplier: u32,
+}
+
+// ? thresholds fastest (shortest interval) first, the first one met is used - an empty curve is no acceleration
+pub type Curve = &'static [Threshold];
+
+pub const NO_ACCELERATION: Curve = &[];
+
+#[derive(Clone, Copy)]
+pub struct Acceleration {
+    // time of the last detent in ms
+    last_detent: Option<u32>,
+}
+
+impl Acceleration {
+    pub const fn new() -> Self {
+        Acceleration { last_detent: None }
+    }
+
+    // ? steps for a detent (1 clockwise, -1 anticlockwise) at the current time in ms
+    pub fn steps(&mut self, step: i8, curve: Curve, now: u32) -> i32 {
+        let multiplier = match self.last_detent {
+            Some(last) => {
+                let interval = now.wrapping_sub(last);
+                curve
+                    .iter()
+                    .find(|threshold| interval <= threshold.interval)
+                    .map_or(1, |threshold| threshold.multiplier)
+            }
+            None => 1,
+        };
+        self.last_detent = Some(now);
+        step as i32 * multiplier as i32
+    }
+}
+
+impl Default for Acceleration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,75 @@
+// Caps word - shift letters (and - to _) until the end of a word, without touching caps lock
+
+use usbd_human_interface_device::page::Keyboard;
+
+// caps word turns off if no key is pressed for this long (ms)
+pub const CAPS_WORD_IDLE_TIMEOUT: u32 = 5000;
+
+pub struct CapsWord {
+    active: bool,
+    // the last key pressed needs shift
+    shift: bool,
+    // time of the last key press
+    last_press: u32,
+}
+
+impl CapsWord {
+    pub const fn new() -> Self {
+        CapsWord {
+            active: false,
+            shift: false,
+            last_press: 0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn toggle(&mut self, now: u32) {
+        self.active = !self.active;
+        self.shift = false;
+        self.last_press = now;
+    }
+
+    // ? a key was pressed - letters and minus are shifted, other word keys carry on, anything else ends the word
+    pub fn key_pressed(&mut self, key: Keyboard, now: u32) {
+        if !self.active {
+            return;
+        }
+        self.last_press = now;
+        let code = key as u8;
+        if (Keyboard::A as u8..=Keyboard::Z as u8).contains(&code) || key == Keyboard::Minus {
+            self.shift = true;
+        } else if (Keyboard::Keyboard1 as u8..=Keyboard::Keyboard0 as u8).contains(&code)
+            || (Keyboard::LeftControl as u8..=Keyboard::RightGUI as u8).contains(&code)
+            || key == Keyboard::DeleteBackspace
+            || key == Keyboard::DeleteForward
+        {
+            self.shift = false;
+        } else {
+            // space, punctuation, enter etc. end the word
+            self.active = false;
+            self.shift = false;
+        }
+    }
+
+    // ? turn off after the idle timeout, call every loop with the current time in ms
+    pub fn tick(&mut self, now: u32) {
+        if self.active && now.wrapping_sub(self.last_press) >= CAPS_WORD_IDLE_TIMEOUT {
+            self.active = false;
+            self.shift = false;
+        }
+    }
+
+    // ? shift needs adding to the report
+    pub fn shift(&self) -> bool {
+        self.active && self.shift
+    }
+}
+
+impl Default for CapsWord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
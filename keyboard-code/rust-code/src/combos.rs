@@ -0,0 +1,265 @@
+// Combos - keys pressed together within a short time send a different action
+
+use crate::events::KeyEvent;
+use crate::keys::{Action, COLS, ROWS};
+use crate::layers::LayerState;
+use heapless::Vec;
+
+// most keys in a single combo
+pub const MAX_COMBO_KEYS: usize = 4;
+// most combos in the combo list
+pub const MAX_COMBOS: usize = 16;
+
+pub struct Combo {
+    // key positions (row, col) that make up the combo
+    pub keys: &'static [(usize, usize)],
+    pub action: Action,
+    // all keys have to be pressed within this time (ms) of the first one
+    pub timeout: u32,
+    // bitmask of layers the combo works on, 0 for all layers
+    pub layers: u32,
+}
+
+// ? what comes out of the combo engine - key events passed through or combo presses/releases
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ComboEvent {
+    Key(KeyEvent),
+    Combo {
+        index: usize,
+        action: Action,
+        pressed: bool,
+    },
+}
+
+pub struct Combos {
+    combos: &'static [Combo],
+    // presses held back while they could still become a combo
+    buffer: Vec<KeyEvent, MAX_COMBO_KEYS>,
+    // combo each key was used for, the combo is released when its first key is released
+    consumed: [[Option<usize>; COLS]; ROWS],
+    // combos currently held down
+    active: [bool; MAX_COMBOS],
+}
+
+impl Combos {
+    pub const fn new(combos: &'static [Combo]) -> Self {
+        Combos {
+            combos,
+            buffer: Vec::new(),
+            consumed: [[None; COLS]; ROWS],
+            active: [false; MAX_COMBOS],
+        }
+    }
+
+    // ? handle a key event - returns the events to pass on to the keymap
+    pub fn event(&mut self, event: KeyEvent, layers: &LayerState) -> Vec<ComboEvent, 8> {
+        let mut out = Vec::new();
+        if event.pressed {
+            if self.buffer.len() < MAX_COMBO_KEYS && self.possible(&event, layers) {
+                self.buffer.push(event).ok();
+                if let Some(index) = self.complete(event.timestamp, layers) {
+                    if !self.longer_possible(event.timestamp, layers) {
+                        self.fire(index, &mut out);
+                    }
+                }
+            } else {
+                self.flush(&mut out, layers);
+                if self.possible(&event, layers) {
+                    self.buffer.push(event).ok();
+                } else {
+                    out.push(ComboEvent::Key(event)).ok();
+                }
+            }
+        } else {
+            if self
+                .buffer
+                .iter()
+                .any(|e| e.row == event.row && e.col == event.col)
+            {
+                // released before the combo was finished - this can still fire a combo the key is part of
+                self.flush(&mut out, layers);
+            }
+            if let Some(index) = self.consumed[event.row][event.col].take() {
+                // key used by a combo - release the combo on the first key up, swallow the rest
+                if self.active[index] {
+                    self.active[index] = false;
+                    out.push(ComboEvent::Combo {
+                        index,
+                        action: self.combos[index].action,
+                        pressed: false,
+                    })
+                    .ok();
+                }
+            } else {
+                out.push(ComboEvent::Key(event)).ok();
+            }
+        }
+        out
+    }
+
+    // ? check combo timeouts, call every loop with the current time in ms
+    pub fn tick(&mut self, now: u32, layers: &LayerState) -> Vec<ComboEvent, 8> {
+        let mut out = Vec::new();
+        if let Some(first) = self.buffer.first() {
+            let elapsed = now.wrapping_sub(first.timestamp);
+            let waiting = self.combos.iter().any(|combo| {
+                elapsed < combo.timeout
+                    && self.enabled(combo, layers)
+                    && self
+                        .buffer
+                        .iter()
+                        .all(|e| combo.keys.contains(&(e.row, e.col)))
+            });
+            if !waiting {
+                self.flush(&mut out, layers);
+            }
+        }
+        out
+    }
+
+    fn enabled(&self, combo: &Combo, layers: &LayerState) -> bool {
+        combo.layers == 0 || layers.iter().any(|layer| combo.layers & (1 << layer) != 0)
+    }
+
+    // time since the first held back press
+    fn elapsed(&self, now: u32) -> u32 {
+        self.buffer
+            .first()
+            .map_or(0, |first| now.wrapping_sub(first.timestamp))
+    }
+
+    // could the held back keys plus this press still become a combo
+    fn possible(&self, event: &KeyEvent, layers: &LayerState) -> bool {
+        let elapsed = self.elapsed(event.timestamp);
+        self.combos.iter().any(|combo| {
+            elapsed < combo.timeout
+                && self.enabled(combo, layers)
+                && combo.keys.contains(&(event.row, event.col))
+                && self
+                    .buffer
+                    .iter()
+                    .all(|e| combo.keys.contains(&(e.row, e.col)))
+        })
+    }
+
+    // combo made up of exactly the held back keys
+    fn complete(&self, now: u32, layers: &LayerState) -> Option<usize> {
+        let elapsed = self.elapsed(now);
+        self.combos.iter().position(|combo| {
+            elapsed < combo.timeout
+                && self.enabled(combo, layers)
+                && combo.keys.len() == self.buffer.len()
+                && self
+                    .buffer
+                    .iter()
+                    .all(|e| combo.keys.contains(&(e.row, e.col)))
+        })
+    }
+
+    // a combo with more keys than those held back could still be finished
+    fn longer_possible(&self, now: u32, layers: &LayerState) -> bool {
+        let elapsed = self.elapsed(now);
+        self.combos.iter().any(|combo| {
+            elapsed < combo.timeout
+                && self.enabled(combo, layers)
+                && combo.keys.len() > self.buffer.len()
+                && self
+                    .buffer
+                    .iter()
+                    .all(|e| combo.keys.contains(&(e.row, e.col)))
+        })
+    }
+
+    fn fire(&mut self, index: usize, out: &mut Vec<ComboEvent, 8>) {
+        for event in self.buffer.iter() {
+            self.consumed[event.row][event.col] = Some(index);
+        }
+        self.buffer.clear();
+        self.active[index] = true;
+        out.push(ComboEvent::Combo {
+            index,
+            action: self.combos[index].action,
+            pressed: true,
+        })
+        .ok();
+    }
+
+    // stop waiting - send the finished combo if there is one, otherwise pass the keys on as they were
+    fn flush(&mut self, out: &mut Vec<ComboEvent, 8>, layers: &LayerState) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let now = self.buffer[self.buffer.len() - 1].timestamp;
+        if let Some(index) = self.complete(now, layers) {
+            self.fire(index, out);
+        } else {
+            for event in self.buffer.iter() {
+                out.push(ComboEvent::Key(*event)).ok();
+            }
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usbd_human_interface_device::page::Keyboard;
+
+    const J: (usize, usize) = (2, 7);
+    const K: (usize, usize) = (2, 8);
+    const L: (usize, usize) = (2, 9);
+
+    // j + k, and j + k + l so j + k has to wait to see if l follows
+    static SUBSET_COMBOS: [Combo; 2] = [
+        Combo {
+            keys: &[J, K],
+            action: Action::Key(Keyboard::Escape),
+            timeout: 50,
+            layers: 0,
+        },
+        Combo {
+            keys: &[J, K, L],
+            action: Action::Key(Keyboard::Tab),
+            timeout: 50,
+            layers: 0,
+        },
+    ];
+
+    fn key(
+        combos: &mut Combos,
+        (row, col): (usize, usize),
+        pressed: bool,
+        at: u32,
+    ) -> std::vec::Vec<ComboEvent> {
+        let layers = LayerState::new(0);
+        combos
+            .event(KeyEvent::test(row, col, pressed, at), &layers)
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn releasing_a_waiting_subset_combo_key_releases_the_combo() {
+        let mut combos = Combos::new(&SUBSET_COMBOS);
+        let combo = |pressed| ComboEvent::Combo {
+            index: 0,
+            action: Action::Key(Keyboard::Escape),
+            pressed,
+        };
+        assert_eq!(key(&mut combos, J, true, 0), []);
+        assert_eq!(key(&mut combos, K, true, 10), []);
+        // j + k + l is still possible until j is released
+        assert_eq!(key(&mut combos, J, false, 20), [combo(true), combo(false)]);
+        assert_eq!(key(&mut combos, K, false, 30), []);
+        // a tap on j afterwards is a plain press and release
+        assert_eq!(key(&mut combos, J, true, 100), []);
+        assert_eq!(
+            key(&mut combos, J, false, 110),
+            [
+                ComboEvent::Key(KeyEvent::test(J.0, J.1, true, 100)),
+                ComboEvent::Key(KeyEvent::test(J.0, J.1, false, 110)),
+            ]
+        );
+    }
+}
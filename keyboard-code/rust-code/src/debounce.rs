@@ -0,0 +1,202 @@
+// Debouncing the matrix scan - timed in ms so it doesn't depend on how long each loop takes
+
+// ? debounce algorithms
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DebounceAlgorithm {
+    // the whole matrix has to be unchanged for the debounce time before any change is let through
+    SymmetricDeferred,
+    // presses are let through straight away, releases have to be unchanged for the debounce time
+    EagerPressDeferredRelease,
+    // each row has to be unchanged for the debounce time
+    DeferredPerRow,
+    // each key has to be unchanged for the debounce time
+    DeferredPerKey,
+}
+
+pub struct Debouncer<const ROWS: usize, const COLS: usize> {
+    algorithm: DebounceAlgorithm,
+    // debounce time (ms)
+    time: u32,
+    // state let through, bit per column
+    debounced: [u32; ROWS],
+    // scan state last seen
+    raw: [u32; ROWS],
+    // time of the last raw change - for the whole matrix, each row and each key
+    matrix_changed: u32,
+    row_changed: [u32; ROWS],
+    key_changed: [[u32; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> {
+    pub const fn new(algorithm: DebounceAlgorithm, time: u32) -> Self {
+        Debouncer {
+            algorithm,
+            time,
+            debounced: [0; ROWS],
+            raw: [0; ROWS],
+            matrix_changed: 0,
+            row_changed: [0; ROWS],
+            key_changed: [[0; COLS]; ROWS],
+        }
+    }
+
+    // ? take a new scan and return the debounced state, call every scan with the current time in ms
+    pub fn update(&mut self, raw: &[u32; ROWS], now: u32) -> &[u32; ROWS] {
+        // note when things changed
+        if *raw != self.raw {
+            self.matrix_changed = now;
+        }
+        for (row, (new, old)) in raw.iter().zip(self.raw.iter()).enumerate() {
+            let changed = new ^ old;
+            if changed != 0 {
+                self.row_changed[row] = now;
+            }
+            for (col, key_changed) in self.key_changed[row].iter_mut().enumerate() {
+                if changed & (1 << col) != 0 {
+                    *key_changed = now;
+                }
+            }
+        }
+        self.raw = *raw;
+
+        let time = self.time;
+        let settled = |changed: u32| now.wrapping_sub(changed) >= time;
+        let rows = self
+            .debounced
+            .iter_mut()
+            .zip(raw.iter())
+            .zip(self.row_changed.iter().zip(self.key_changed.iter()));
+        match self.algorithm {
+            DebounceAlgorithm::SymmetricDeferred => {
+                if settled(self.matrix_changed) {
+                    self.debounced = *raw;
+                }
+            }
+            DebounceAlgorithm::DeferredPerRow => {
+                for ((debounced, raw), (row_changed, _)) in rows {
+                    if settled(*row_changed) {
+                        *debounced = *raw;
+                    }
+                }
+            }
+            DebounceAlgorithm::DeferredPerKey => {
+                for ((debounced, raw), (_, key_changed)) in rows {
+                    for (col, changed) in key_changed.iter().enumerate() {
+                        if settled(*changed) {
+                            let bit = 1 << col;
+                            *debounced = (*debounced & !bit) | (raw & bit);
+                        }
+                    }
+                }
+            }
+            DebounceAlgorithm::EagerPressDeferredRelease => {
+                for ((debounced, raw), (_, key_changed)) in rows {
+                    // presses straight away - bounces after are ignored as the release is deferred
+                    *debounced |= raw;
+                    for (col, changed) in key_changed.iter().enumerate() {
+                        let bit = 1 << col;
+                        if raw & bit == 0 && settled(*changed) {
+                            *debounced &= !bit;
+                        }
+                    }
+                }
+            }
+        }
+        &self.debounced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ? scripted waveform - (time ms, row, col, pressed) edges of the raw scan
+    // a (0, 0) - pressed with a bounce burst from 10 (settled at 14), released with a bounce from 50 (settled at 52)
+    // b (0, 1) - chatters from 15 to 18 in the same row as a
+    // c (1, 0) - chatters at 20 and 21 in another row
+    const WAVEFORM: &[(u32, usize, usize, bool)] = &[
+        (10, 0, 0, true),
+        (11, 0, 0, false),
+        (12, 0, 0, true),
+        (13, 0, 0, false),
+        (14, 0, 0, true),
+        (15, 0, 1, true),
+        (16, 0, 1, false),
+        (17, 0, 1, true),
+        (18, 0, 1, false),
+        (20, 1, 0, true),
+        (21, 1, 0, false),
+        (50, 0, 0, false),
+        (51, 0, 0, true),
+        (52, 0, 0, false),
+    ];
+
+    const A: u32 = 0b01;
+    const B: u32 = 0b10;
+
+    // scan every ms with a 5ms debounce, returns the time and state of every debounced change
+    fn run(algorithm: DebounceAlgorithm) -> std::vec::Vec<(u32, [u32; 2])> {
+        let mut debouncer: Debouncer<2, 2> = Debouncer::new(algorithm, 5);
+        let mut raw = [0; 2];
+        let mut last = [0; 2];
+        let mut changes = std::vec::Vec::new();
+        for now in 1..100 {
+            for (_, row, col, pressed) in WAVEFORM.iter().filter(|edge| edge.0 == now) {
+                if *pressed {
+                    raw[*row] |= 1 << col;
+                } else {
+                    raw[*row] &= !(1 << col);
+                }
+            }
+            let debounced = *debouncer.update(&raw, now);
+            if debounced != last {
+                changes.push((now, debounced));
+                last = debounced;
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn symmetric_deferred_waits_for_the_whole_matrix() {
+        // c settles last at 21
+        assert_eq!(
+            run(DebounceAlgorithm::SymmetricDeferred),
+            [(26, [A, 0]), (57, [0, 0])]
+        );
+    }
+
+    #[test]
+    fn deferred_per_row_waits_for_the_row() {
+        // b settles at 18, c's row doesn't matter
+        assert_eq!(
+            run(DebounceAlgorithm::DeferredPerRow),
+            [(23, [A, 0]), (57, [0, 0])]
+        );
+    }
+
+    #[test]
+    fn deferred_per_key_waits_for_the_key() {
+        // the chatter on b and c never settles pressed
+        assert_eq!(
+            run(DebounceAlgorithm::DeferredPerKey),
+            [(19, [A, 0]), (57, [0, 0])]
+        );
+    }
+
+    #[test]
+    fn eager_press_deferred_release() {
+        // presses straight away, bounces while pressed are ignored, releases once settled
+        assert_eq!(
+            run(DebounceAlgorithm::EagerPressDeferredRelease),
+            [
+                (10, [A, 0]),
+                (15, [A | B, 0]),
+                (20, [A | B, A]),
+                (23, [A, A]),
+                (26, [A, 0]),
+                (57, [0, 0]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,268 @@
+// Matrix diagnostics - per key chatter and ghost statistics to find failing switches and diodes, read by the host over a vendor hid interface
+
+use crate::matrix::ScanTiming;
+
+// presses of the same key closer together than this (ms) are suspected chatter
+pub const CHATTER_INTERVAL: u32 = 30;
+
+// ? vendor defined hid report - 32 bytes in and 32 bytes out
+#[rustfmt::skip]
+pub const DIAGNOSTICS_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61, //       Usage (0x61)
+    0xA1, 0x01, //       Collection (Application)
+    0x09, 0x62, //           Usage (0x62)
+    0x15, 0x00, //           Logical Minimum (0)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x95, 0x20, //           Report Count (32)
+    0x75, 0x08, //           Report Size (8)
+    0x81, 0x02, //           Input (Data,Var,Abs)
+    0x09, 0x63, //           Usage (0x63)
+    0x15, 0x00, //           Logical Minimum (0)
+    0x26, 0xFF, 0x00, //     Logical Maximum (255)
+    0x95, 0x20, //           Report Count (32)
+    0x75, 0x08, //           Report Size (8)
+    0x91, 0x02, //           Output (Data,Var,Abs)
+    0xC0, //             End Collection
+];
+pub const REPORT_SIZE: usize = 32;
+
+// ? host requests - the first byte of the output report, the reply starts with the same byte
+// [KEY_STATS, row, col] - reply [KEY_STATS, row, col, 0, presses, bounces, shortest interval (ms, 0 for none), chatter, ghosts]
+// as u32 little endian
+pub const KEY_STATS: u8 = 0x01;
+// [SCAN_STATS] - reply [SCAN_STATS, 0, 0, 0, frequency (Hz), jitter (us), ghost events] as u32 little endian
+pub const SCAN_STATS: u8 = 0x02;
+// [RESET_STATS] - clear the key statistics, reply [RESET_STATS]
+pub const RESET_STATS: u8 = 0x03;
+// [MATRIX_TESTER, 1 on / 0 off] - reply [MATRIX_TESTER, 1 on / 0 off], the mode itself is switched in main
+pub const MATRIX_TESTER: u8 = 0x04;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyStats {
+    // debounced presses
+    pub presses: u32,
+    // extra changes of the raw scan before it settled
+    pub bounces: u32,
+    // shortest time (ms) between two presses, u32::MAX until the key has been pressed twice
+    pub shortest_interval: u32,
+    // presses closer together than CHATTER_INTERVAL
+    pub chatter: u32,
+    // times the key was marked as a possible ghost
+    pub ghosts: u32,
+}
+
+impl KeyStats {
+    pub const fn new() -> Self {
+        KeyStats {
+            presses: 0,
+            bounces: 0,
+            shortest_interval: u32::MAX,
+            chatter: 0,
+            ghosts: 0,
+        }
+    }
+}
+
+impl Default for KeyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct MatrixStats<const ROWS: usize, const COLS: usize> {
+    keys: [[KeyStats; COLS]; ROWS],
+    // raw and debounced state at the last scan
+    raw: [u32; ROWS],
+    debounced: [u32; ROWS],
+    // keys marked as possible ghosts at the last scan
+    suspect: [u32; ROWS],
+    // raw changes since the last debounced change
+    raw_changes: [[u32; COLS]; ROWS],
+    // time of the last debounced press
+    last_press: [[Option<u32>; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> MatrixStats<ROWS, COLS> {
+    pub const fn new() -> Self {
+        MatrixStats {
+            keys: [[KeyStats::new(); COLS]; ROWS],
+            raw: [0; ROWS],
+            debounced: [0; ROWS],
+            suspect: [0; ROWS],
+            raw_changes: [[0; COLS]; ROWS],
+            last_press: [[None; COLS]; ROWS],
+        }
+    }
+
+    // ? compare the raw and debounced scans, call every scan with the current time in ms
+    // suspect - keys the ghosting check marked as possible ghosts
+    pub fn update(
+        &mut self,
+        raw: &[u32; ROWS],
+        debounced: &[u32; ROWS],
+        suspect: &[u32; ROWS],
+        now: u32,
+    ) {
+        for row in 0..ROWS {
+            let raw_changed = raw[row] ^ self.raw[row];
+            let debounced_changed = debounced[row] ^ self.debounced[row];
+            let newly_suspect = suspect[row] & !self.suspect[row];
+            for col in 0..COLS {
+                let bit = 1 << col;
+                if raw_changed & bit != 0 {
+                    self.raw_changes[row][col] += 1;
+                }
+                if newly_suspect & bit != 0 {
+                    self.keys[row][col].ghosts += 1;
+                }
+                if debounced_changed & bit == 0 {
+                    continue;
+                }
+                // every raw change apart from the one that stuck was a bounce
+                let stats = &mut self.keys[row][col];
+                stats.bounces += self.raw_changes[row][col].saturating_sub(1);
+                self.raw_changes[row][col] = 0;
+                if debounced[row] & bit != 0 {
+                    stats.presses += 1;
+                    if let Some(last) = self.last_press[row][col] {
+                        let interval = now.wrapping_sub(last);
+                        stats.shortest_interval = stats.shortest_interval.min(interval);
+                        if interval < CHATTER_INTERVAL {
+                            stats.chatter += 1;
+                        }
+                    }
+                    self.last_press[row][col] = Some(now);
+                }
+            }
+        }
+        self.raw = *raw;
+        self.debounced = *debounced;
+        self.suspect = *suspect;
+    }
+
+    pub fn key(&self, row: usize, col: usize) -> &KeyStats {
+        &self.keys[row][col]
+    }
+
+    pub fn reset(&mut self) {
+        self.keys = [[KeyStats::new(); COLS]; ROWS];
+        self.last_press = [[None; COLS]; ROWS];
+    }
+
+    // ? bounces over every key
+    pub fn total_bounces(&self) -> u32 {
+        self.keys
+            .iter()
+            .flatten()
+            .fold(0, |total, stats| total.saturating_add(stats.bounces))
+    }
+
+    // ? key with the most chatter (row, col), None if no key has chattered
+    pub fn worst(&self) -> Option<(usize, usize)> {
+        let mut worst = None;
+        let mut most = 0;
+        for (row, keys) in self.keys.iter().enumerate() {
+            for (col, stats) in keys.iter().enumerate() {
+                if stats.chatter > most {
+                    most = stats.chatter;
+                    worst = Some((row, col));
+                }
+            }
+        }
+        worst
+    }
+
+    // ? reply to a host request, None if the request isn't known
+    pub fn respond(
+        &mut self,
+        request: &[u8],
+        timing: &ScanTiming,
+        ghost_events: u32,
+    ) -> Option<[u8; REPORT_SIZE]> {
+        let mut reply = [0; REPORT_SIZE];
+        let command = *request.first()?;
+        reply[0] = command;
+        match command {
+            KEY_STATS => {
+                let row = *request.get(1)? as usize;
+                let col = *request.get(2)? as usize;
+                if row >= ROWS || col >= COLS {
+                    return None;
+                }
+                let stats = self.keys[row][col];
+                let shortest = if stats.shortest_interval == u32::MAX {
+                    0
+                } else {
+                    stats.shortest_interval
+                };
+                reply[1] = row as u8;
+                reply[2] = col as u8;
+                put(
+                    &mut reply,
+                    &[
+                        stats.presses,
+                        stats.bounces,
+                        shortest,
+                        stats.chatter,
+                        stats.ghosts,
+                    ],
+                );
+            }
+            SCAN_STATS => put(&mut reply, &[timing.frequency, timing.jitter, ghost_events]),
+            RESET_STATS => self.reset(),
+            MATRIX_TESTER => reply[1] = (*request.get(1)? != 0) as u8,
+            _ => return None,
+        }
+        Some(reply)
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for MatrixStats<ROWS, COLS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// values after the 4 byte header, little endian
+fn put(reply: &mut [u8; REPORT_SIZE], values: &[u32]) {
+    for (chunk, value) in reply[4..].chunks_exact_mut(4).zip(values.iter()) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::{GhostPolicy, Ghosting};
+
+    fn key_stats(stats: &mut MatrixStats<3, 4>, row: u8, col: u8) -> [u32; 5] {
+        let reply = stats
+            .respond(&[KEY_STATS, row, col], &ScanTiming::new(), 0)
+            .unwrap();
+        let mut values = [0; 5];
+        for (value, chunk) in values.iter_mut().zip(reply[4..].chunks_exact(4)) {
+            *value = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        values
+    }
+
+    #[test]
+    fn marked_ghosts_are_counted_per_key() {
+        let mut ghosting: Ghosting<3> = Ghosting::new(GhostPolicy::Mark);
+        let mut stats: MatrixStats<3, 4> = MatrixStats::new();
+        // (0, 0) (0, 1) (1, 0) held, then (1, 1) completes the rectangle
+        for (now, scan) in [[0b01, 0, 0], [0b11, 0, 0], [0b11, 0b01, 0], [0b11, 0b11, 0]]
+            .into_iter()
+            .enumerate()
+        {
+            let mut scan = scan;
+            ghosting.filter(&mut scan);
+            stats.update(&scan, &scan, ghosting.suspect(), now as u32 * 100);
+        }
+        assert_eq!(key_stats(&mut stats, 1, 1)[4], 1);
+        assert_eq!(key_stats(&mut stats, 0, 0)[4], 0);
+        // presses, bounces, shortest interval, chatter
+        assert_eq!(key_stats(&mut stats, 1, 1)[..4], [1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,65 @@
+// Dynamic macros - record key presses into ram on the keyboard and play them back
+
+use crate::macros::MacroStep;
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard;
+
+// number of macros that can be recorded
+pub const DYNAMIC_MACRO_SLOTS: usize = 2;
+// most presses and releases in one recorded macro
+pub const DYNAMIC_MACRO_SIZE: usize = 128;
+
+pub struct DynamicMacros {
+    slots: [Vec<MacroStep, DYNAMIC_MACRO_SIZE>; DYNAMIC_MACRO_SLOTS],
+    // slot being recorded into
+    recording: Option<usize>,
+}
+
+impl DynamicMacros {
+    pub const fn new() -> Self {
+        DynamicMacros {
+            slots: [Vec::new(), Vec::new()],
+            recording: None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // ? record key pressed - starts recording into the slot, or stops if already recording
+    pub fn toggle_recording(&mut self, slot: usize) {
+        if self.recording.is_some() {
+            self.recording = None;
+        } else if slot < DYNAMIC_MACRO_SLOTS {
+            self.slots[slot].clear();
+            self.recording = Some(slot);
+        }
+    }
+
+    // ? add a key press or release to the macro being recorded
+    pub fn record(&mut self, key: Keyboard, pressed: bool) {
+        if let Some(slot) = self.recording {
+            let step = if pressed {
+                MacroStep::Press(key)
+            } else {
+                MacroStep::Release(key)
+            };
+            if self.slots[slot].push(step).is_err() {
+                // out of space - stop here, anything left held is released when played back
+                self.recording = None;
+            }
+        }
+    }
+
+    // ? steps recorded into a slot
+    pub fn steps(&self, slot: usize) -> &[MacroStep] {
+        self.slots.get(slot).map_or(&[], |steps| steps.as_slice())
+    }
+}
+
+impl Default for DynamicMacros {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,177 @@
+// Rotary encoders - decoding the pins and turned detents waiting to be pressed and released one report at a time
+
+use crate::acceleration::Acceleration;
+use crate::keys::{Action, EncoderTurn};
+use crate::quadrature::{Quadrature, Resolution};
+use embedded_hal::digital::v2::InputPin;
+
+// ? an encoder on the board - the pins are given in main, bindings for each layer are in the keymap
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EncoderConfig {
+    pub resolution: Resolution,
+    // matrix position (row, col) of the push switch, None if there isn't one
+    pub push: Option<(usize, usize)>,
+}
+
+// ? a and b are pulled up inputs, clockwise turns pull b low before a
+pub struct Encoder<P: InputPin> {
+    a: P,
+    b: P,
+    push: Option<(usize, usize)>,
+    decoder: Quadrature,
+}
+
+impl<P: InputPin> Encoder<P> {
+    pub fn new(config: &EncoderConfig, a: P, b: P) -> Self {
+        Encoder {
+            a,
+            b,
+            push: config.push,
+            decoder: Quadrature::new(config.resolution),
+        }
+    }
+
+    // ? read the pins, returns 1 for a clockwise detent, -1 anticlockwise, 0 none - call as often as possible
+    pub fn update(&mut self) -> Result<i8, P::Error> {
+        // pulled up - low is closed
+        let a = self.a.is_low()?;
+        let b = self.b.is_low()?;
+        Ok(self.decoder.update(a, b))
+    }
+
+    // ? matrix position of the push switch
+    pub fn push(&self) -> Option<(usize, usize)> {
+        self.push
+    }
+
+    // ? the a and b pins, e.g. to wake from idle sleep when the encoder moves
+    pub fn pins(&self) -> (&P, &P) {
+        (&self.a, &self.b)
+    }
+}
+
+// ? key and consumer steps are pressed in one report and released in the next, wheel steps take one report each,
+// so a fast spin isn't collapsed into one step
+#[derive(Clone, Copy)]
+pub struct Rotation {
+    // steps not yet pressed, positive is clockwise
+    steps: i32,
+    // actions the steps are for
+    turn: EncoderTurn,
+    // step pressed in the last report, waiting to be released
+    held: Option<Action>,
+    acceleration: Acceleration,
+}
+
+impl Rotation {
+    pub const fn new() -> Self {
+        Rotation {
+            steps: 0,
+            turn: EncoderTurn::NONE,
+            held: None,
+            acceleration: Acceleration::new(),
+        }
+    }
+
+    // ? add a detent (1 clockwise, -1 anticlockwise) turned with the given binding at the current time in ms
+    pub fn turn(&mut self, step: i8, turn: EncoderTurn, now: u32) {
+        if turn.clockwise != self.turn.clockwise || turn.anticlockwise != self.turn.anticlockwise {
+            // steps left over from another binding are dropped
+            self.steps = 0;
+        }
+        self.turn = turn;
+        self.steps += self.acceleration.steps(step, turn.curve, now);
+    }
+
+    // ? action of the next step, None while a step is held or no steps are waiting
+    pub fn next(&self) -> Option<Action> {
+        if self.held.is_some() {
+            return None;
+        }
+        match self.steps.signum() {
+            1 => Some(self.turn.clockwise),
+            -1 => Some(self.turn.anticlockwise),
+            _ => None,
+        }
+    }
+
+    // ? step waiting to be released
+    pub fn held(&self) -> Option<Action> {
+        self.held
+    }
+
+    // ? the step from next() was sent pressed
+    pub fn pressed(&mut self) {
+        self.held = self.next();
+        self.steps -= self.steps.signum();
+    }
+
+    // ? the held step was sent released
+    pub fn released(&mut self) {
+        self.held = None;
+    }
+
+    // ? the step from next() was used up without being held (a wheel step, or a layer change)
+    pub fn skip(&mut self) {
+        self.steps -= self.steps.signum();
+    }
+
+    // ? no steps waiting and nothing held
+    pub fn is_idle(&self) -> bool {
+        self.steps == 0 && self.held.is_none()
+    }
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    // pin reading a shared level, true for high
+    struct MockPin<'a>(&'a Cell<bool>);
+
+    impl InputPin for MockPin<'_> {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(!self.0.get())
+        }
+    }
+
+    #[test]
+    fn pulled_low_pins_are_decoded() {
+        let (a, b) = (Cell::new(true), Cell::new(true));
+        let config = EncoderConfig {
+            resolution: Resolution::Full,
+            push: Some((1, 13)),
+        };
+        let mut encoder = Encoder::new(&config, MockPin(&a), MockPin(&b));
+        assert_eq!(encoder.push(), Some((1, 13)));
+        let mut turn = |levels: &[(bool, bool)]| -> std::vec::Vec<i8> {
+            levels
+                .iter()
+                .map(|(a_level, b_level)| {
+                    a.set(*a_level);
+                    b.set(*b_level);
+                    encoder.update().unwrap()
+                })
+                .collect()
+        };
+        // b goes low first clockwise
+        let clockwise = [(true, false), (false, false), (false, true), (true, true)];
+        let anticlockwise = [(false, true), (false, false), (true, false), (true, true)];
+        assert_eq!(turn(&clockwise), [0, 0, 0, 1]);
+        assert_eq!(turn(&anticlockwise), [0, 0, 0, -1]);
+    }
+}
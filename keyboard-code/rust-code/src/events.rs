@@ -0,0 +1,141 @@
+// Key events - presses and releases with the time they happened
+
+use crate::key_state::KeyState;
+use heapless::Deque;
+
+// most events waiting to be handled
+pub const EVENT_QUEUE_SIZE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyEvent {
+    pub row: usize,
+    pub col: usize,
+    // true for a press, false for a release
+    pub pressed: bool,
+    // time of the event in ms
+    pub timestamp: u32,
+}
+
+#[cfg(test)]
+impl KeyEvent {
+    // ? event for the tests
+    pub fn test(row: usize, col: usize, pressed: bool, timestamp: u32) -> Self {
+        KeyEvent {
+            row,
+            col,
+            pressed,
+            timestamp,
+        }
+    }
+}
+
+// ? turns debounced scans into a queue of key events for the layers, combos etc. to take in order
+pub struct KeyEvents {
+    // key state the queued events lead up to
+    state: KeyState,
+    queue: Deque<KeyEvent, EVENT_QUEUE_SIZE>,
+}
+
+impl KeyEvents {
+    pub const fn new() -> Self {
+        KeyEvents {
+            state: KeyState::new(),
+            queue: Deque::new(),
+        }
+    }
+
+    // ? queue an event for every key that changed, call every scan with the current time in ms
+    // if the queue is full the rest are left for the next scan, so no press or release is lost
+    pub fn update(&mut self, keys: &KeyState, timestamp: u32) {
+        let last = self.state;
+        for (row, col) in keys.changed(&last) {
+            let pressed = keys.is_pressed(row, col);
+            let event = KeyEvent {
+                row,
+                col,
+                pressed,
+                timestamp,
+            };
+            if self.queue.push_back(event).is_err() {
+                break;
+            }
+            self.state.set(row, col, pressed);
+        }
+    }
+
+    // ? next event, oldest first
+    pub fn pop(&mut self) -> Option<KeyEvent> {
+        self.queue.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // ? key state once every queued event has been handled
+    pub fn state(&self) -> &KeyState {
+        &self.state
+    }
+}
+
+impl Default for KeyEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{COLS, ROWS};
+
+    fn pop_all(events: &mut KeyEvents) -> std::vec::Vec<KeyEvent> {
+        core::iter::from_fn(|| events.pop()).collect()
+    }
+
+    #[test]
+    fn events_are_queued_in_order_with_the_scan_time() {
+        let mut events = KeyEvents::new();
+        let mut keys = KeyState::new();
+        keys.set(2, 1, true);
+        keys.set(0, 3, true);
+        events.update(&keys, 10);
+        keys.set(0, 3, false);
+        events.update(&keys, 15);
+        // nothing changed
+        events.update(&keys, 20);
+        assert_eq!(
+            pop_all(&mut events),
+            [
+                KeyEvent::test(0, 3, true, 10),
+                KeyEvent::test(2, 1, true, 10),
+                KeyEvent::test(0, 3, false, 15)
+            ]
+        );
+        assert!(events.is_empty());
+        assert_eq!(*events.state(), keys);
+    }
+
+    #[test]
+    fn a_full_queue_carries_over_to_the_next_scan() {
+        let mut events = KeyEvents::new();
+        let all = KeyState::from_scan(&[u32::MAX; ROWS]);
+        let mut expected = (0..ROWS).flat_map(|row| (0..COLS).map(move |col| (row, col)));
+
+        events.update(&all, 1);
+        // only the queued keys count towards the state
+        assert_eq!(events.state().count(), EVENT_QUEUE_SIZE as u32);
+        for timestamp in [1, 5, 9] {
+            let queued = pop_all(&mut events);
+            assert!(!queued.is_empty());
+            for queued in queued {
+                let (row, col) = expected.next().unwrap();
+                assert_eq!(queued, KeyEvent::test(row, col, true, timestamp));
+            }
+            events.update(&all, timestamp + 4);
+        }
+        assert_eq!(expected.next(), None);
+        assert!(events.is_empty());
+        assert_eq!(*events.state(), all);
+    }
+}
@@ -0,0 +1,60 @@
+// Idle sleep - core0 waits for a key or the encoder with WFI instead of scanning the matrix
+
+use rp2040_hal::pac::{self, Interrupt, NVIC};
+
+// ? which edges of a pin wake core0
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Wake {
+    // a row pulled low by a key press (with every column driven low)
+    Falling,
+    // the encoder moving either way
+    Both,
+}
+
+// interrupt bits for each gpio - level low, level high, edge low, edge high
+fn edge_bits(pin: u8, wake: Wake) -> (usize, u32) {
+    let bits = match wake {
+        Wake::Falling => 0b0100,
+        Wake::Both => 0b1100,
+    };
+    (pin as usize / 8, bits << ((pin % 8) * 4))
+}
+
+// ? sleep core0 until one of the pins changes or the usb needs polling, straight back if a row is already low
+// the interrupts are only used to wake up - they are never taken, so no handlers are needed
+pub fn sleep(wake_pins: &[(u8, Wake)]) {
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    for (pin, wake) in wake_pins.iter() {
+        let (reg, bits) = edge_bits(*pin, *wake);
+        // clear any old edges first
+        io.intr[reg].write(|w| unsafe { w.bits(bits) });
+        io.proc0_inte[reg].modify(|r, w| unsafe { w.bits(r.bits() | bits) });
+    }
+
+    cortex_m::interrupt::free(|_| {
+        unsafe {
+            NVIC::unmask(Interrupt::IO_IRQ_BANK0);
+            NVIC::unmask(Interrupt::USBCTRL_IRQ);
+        }
+        // a key pressed before the edges were cleared has no edge left to wake on - its row is already low
+        let sio = unsafe { &*pac::SIO::ptr() };
+        let levels = sio.gpio_in.read().bits();
+        let pressed = wake_pins
+            .iter()
+            .any(|(pin, wake)| *wake == Wake::Falling && levels & (1 << pin) == 0);
+        if !pressed {
+            // wakes on a pending interrupt even with interrupts disabled
+            cortex_m::asm::wfi();
+        }
+        NVIC::mask(Interrupt::IO_IRQ_BANK0);
+        NVIC::mask(Interrupt::USBCTRL_IRQ);
+        NVIC::unpend(Interrupt::IO_IRQ_BANK0);
+        NVIC::unpend(Interrupt::USBCTRL_IRQ);
+    });
+
+    for (pin, wake) in wake_pins.iter() {
+        let (reg, bits) = edge_bits(*pin, *wake);
+        io.proc0_inte[reg].modify(|r, w| unsafe { w.bits(r.bits() & !bits) });
+        io.intr[reg].write(|w| unsafe { w.bits(bits) });
+    }
+}
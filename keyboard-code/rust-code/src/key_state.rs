@@ -0,0 +1,143 @@
+// Key state - which keys in the matrix are pressed, a bit per key
+
+use crate::keys::{COLS, ROWS};
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct KeyState {
+    // bit n of each row is the key in column n
+    rows: [u16; ROWS],
+}
+
+impl KeyState {
+    pub const fn new() -> Self {
+        KeyState { rows: [0; ROWS] }
+    }
+
+    // ? from a matrix scan - bits past the last column are left out
+    pub fn from_scan(scan: &[u32; ROWS]) -> Self {
+        let mut rows = [0; ROWS];
+        for (row, scan_row) in rows.iter_mut().zip(scan.iter()) {
+            *row = (scan_row & ((1 << COLS) - 1)) as u16;
+        }
+        KeyState { rows }
+    }
+
+    pub fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.rows[row] & (1 << col) != 0
+    }
+
+    pub fn set(&mut self, row: usize, col: usize, pressed: bool) {
+        if pressed {
+            self.rows[row] |= 1 << col;
+        } else {
+            self.rows[row] &= !(1 << col);
+        }
+    }
+
+    // ? keys pressed in a row, bit per column
+    pub fn row(&self, row: usize) -> u16 {
+        self.rows[row]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.iter().all(|row| *row == 0)
+    }
+
+    // ? number of keys pressed
+    pub fn count(&self) -> u32 {
+        self.rows.iter().map(|row| row.count_ones()).sum()
+    }
+
+    // ? (row, col) of every pressed key
+    pub fn pressed(&self) -> impl Iterator<Item = (usize, usize)> {
+        keys(self.rows)
+    }
+
+    // ? (row, col) of every key that is different from the last state
+    pub fn changed(&self, last: &KeyState) -> impl Iterator<Item = (usize, usize)> {
+        keys(self.combine(last, |now, last| now ^ last))
+    }
+
+    // ? (row, col) of every key pressed since the last state
+    pub fn just_pressed(&self, last: &KeyState) -> impl Iterator<Item = (usize, usize)> {
+        keys(self.combine(last, |now, last| now & !last))
+    }
+
+    // ? (row, col) of every key released since the last state
+    pub fn just_released(&self, last: &KeyState) -> impl Iterator<Item = (usize, usize)> {
+        keys(self.combine(last, |now, last| !now & last))
+    }
+
+    fn combine(&self, last: &KeyState, f: impl Fn(u16, u16) -> u16) -> [u16; ROWS] {
+        let mut rows = [0; ROWS];
+        for (row, (now, last)) in rows.iter_mut().zip(self.rows.iter().zip(last.rows.iter())) {
+            *row = f(*now, *last);
+        }
+        rows
+    }
+}
+
+// every set bit as (row, col)
+fn keys(rows: [u16; ROWS]) -> impl Iterator<Item = (usize, usize)> {
+    (0..ROWS).flat_map(move |row| {
+        (0..COLS)
+            .filter(move |col| rows[row] & (1 << col) != 0)
+            .map(move |col| (row, col))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(state: impl Iterator<Item = (usize, usize)>) -> std::vec::Vec<(usize, usize)> {
+        state.collect()
+    }
+
+    #[test]
+    fn from_scan_leaves_out_bits_past_the_last_column() {
+        let mut scan = [0; ROWS];
+        scan[0] = 0b101 | 1 << COLS | 1 << 31;
+        scan[4] = 1 << (COLS - 1);
+        let state = KeyState::from_scan(&scan);
+        assert_eq!(state.row(0), 0b101);
+        assert_eq!(list(state.pressed()), [(0, 0), (0, 2), (4, COLS - 1)]);
+        assert_eq!(state.count(), 3);
+        assert!(KeyState::from_scan(&[1 << COLS; ROWS]).is_empty());
+    }
+
+    #[test]
+    fn set_presses_and_releases_one_key() {
+        let mut state = KeyState::new();
+        assert!(state.is_empty());
+        state.set(2, 5, true);
+        state.set(3, 0, true);
+        assert!(state.is_pressed(2, 5) && state.is_pressed(3, 0));
+        assert_eq!(state.count(), 2);
+        state.set(2, 5, false);
+        // releasing twice changes nothing
+        state.set(2, 5, false);
+        assert!(!state.is_pressed(2, 5));
+        assert_eq!(list(state.pressed()), [(3, 0)]);
+        assert_eq!(state.count(), 1);
+    }
+
+    #[test]
+    fn edges_of_several_keys_in_one_scan() {
+        let mut last = KeyState::new();
+        last.set(0, 1, true);
+        last.set(1, 3, true);
+        last.set(4, 0, true);
+        let mut now = last;
+        // two presses and two releases in the same scan, one key held throughout
+        now.set(0, 2, true);
+        now.set(3, 13, true);
+        now.set(0, 1, false);
+        now.set(4, 0, false);
+        assert_eq!(list(now.just_pressed(&last)), [(0, 2), (3, 13)]);
+        assert_eq!(list(now.just_released(&last)), [(0, 1), (4, 0)]);
+        assert_eq!(list(now.changed(&last)), [(0, 1), (0, 2), (3, 13), (4, 0)]);
+        assert_eq!(list(now.just_pressed(&now)), []);
+        assert_eq!(list(now.just_released(&now)), []);
+    }
+}
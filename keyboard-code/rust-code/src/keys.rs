@@ -1,646 +1,839 @@
 // Aleksas Girenas 23/10/2022
 // Keyboard key functions and assignments
 
+use crate::acceleration::{Curve, Threshold, NO_ACCELERATION};
+use crate::caps_word::CapsWord;
+use crate::combos::{Combo, ComboEvent, MAX_COMBOS};
+use crate::dynamic_macro::DynamicMacros;
+use crate::encoder::{EncoderConfig, Rotation};
+use crate::events::KeyEvent;
+use crate::layers::LayerState;
+use crate::macros::{MacroPlayer, MacroSource, MacroStep};
+use crate::oneshot::OneShotMods;
+use crate::quadrature::Resolution;
+use crate::tap_dance::{TapDanceDef, TapDances};
+use crate::tap_hold::{Decision, TapHold, TapHoldConfig};
+use heapless::Vec;
+use usbd_human_interface_device::page::Consumer;
 use usbd_human_interface_device::page::Keyboard;
+use usbd_human_interface_device::page::Keyboard as K;
 
-// ? 63 keys excluding fn key and consumer - normal layer
-pub fn get_keys(keys: [[i32; 14]; 5]) -> [Keyboard; 63] {
-    [
-        if keys[0][0] == 1 {
-            Keyboard::Escape
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][1] == 1 {
-            Keyboard::Keyboard1
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][2] == 1 {
-            Keyboard::Keyboard2
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][3] == 1 {
-            Keyboard::Keyboard3
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][4] == 1 {
-            Keyboard::Keyboard4
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][5] == 1 {
-            Keyboard::Keyboard5
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][6] == 1 {
-            Keyboard::Keyboard6
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][7] == 1 {
-            Keyboard::Keyboard7
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][8] == 1 {
-            Keyboard::Keyboard8
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][9] == 1 {
-            Keyboard::Keyboard9
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][10] == 1 {
-            Keyboard::Keyboard0
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][11] == 1 {
-            Keyboard::Minus
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][12] == 1 {
-            Keyboard::Equal
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][13] == 1 {
-            Keyboard::DeleteBackspace
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][0] == 1 {
-            Keyboard::Tab
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][1] == 1 {
-            Keyboard::Q
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][2] == 1 {
-            Keyboard::W
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][3] == 1 {
-            Keyboard::E
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][4] == 1 {
-            Keyboard::R
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][5] == 1 {
-            Keyboard::T
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][6] == 1 {
-            Keyboard::Y
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][7] == 1 {
-            Keyboard::U
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][8] == 1 {
-            Keyboard::I
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][9] == 1 {
-            Keyboard::O
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][10] == 1 {
-            Keyboard::P
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][11] == 1 {
-            Keyboard::LeftBrace
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][12] == 1 {
-            Keyboard::RightBrace
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][0] == 1 {
-            Keyboard::CapsLock
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][1] == 1 {
-            Keyboard::A
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][2] == 1 {
-            Keyboard::S
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][3] == 1 {
-            Keyboard::D
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][4] == 1 {
-            Keyboard::F
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][5] == 1 {
-            Keyboard::G
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][6] == 1 {
-            Keyboard::H
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][7] == 1 {
-            Keyboard::J
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][8] == 1 {
-            Keyboard::K
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][9] == 1 {
-            Keyboard::L
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][10] == 1 {
-            Keyboard::Semicolon
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][11] == 1 {
-            Keyboard::Apostrophe
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][12] == 1 {
-            Keyboard::ReturnEnter
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][13] == 1 {
-            Keyboard::NonUSHash
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][0] == 1 {
-            Keyboard::LeftShift
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][1] == 1 {
-            Keyboard::NonUSBackslash
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][2] == 1 {
-            Keyboard::Z
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][3] == 1 {
-            Keyboard::X
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][4] == 1 {
-            Keyboard::C
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][5] == 1 {
-            Keyboard::V
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][6] == 1 {
-            Keyboard::B
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][7] == 1 {
-            Keyboard::N
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][8] == 1 {
-            Keyboard::M
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][9] == 1 {
-            Keyboard::Comma
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][10] == 1 {
-            Keyboard::Dot
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][11] == 1 {
-            Keyboard::ForwardSlash
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][12] == 1 {
-            Keyboard::RightShift
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][13] == 1 {
-            Keyboard::UpArrow
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][0] == 1 {
-            Keyboard::LeftControl
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][1] == 1 {
-            Keyboard::LeftGUI
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][2] == 1 {
-            Keyboard::LeftAlt
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][6] == 1 {
-            Keyboard::Space
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][9] == 1 {
-            Keyboard::RightAlt
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][11] == 1 {
-            Keyboard::LeftArrow
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][12] == 1 {
-            Keyboard::DownArrow
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][13] == 1 {
-            Keyboard::RightArrow
-        } else {
-            Keyboard::NoEventIndicated
-        },
-    ]
+// matrix size
+pub const ROWS: usize = 5;
+pub const COLS: usize = 14;
+
+// ? rotary encoders - one step per detent, the push is a key in the matrix (pins are set in main)
+pub const ENCODERS: usize = 1;
+pub static ENCODER_CONFIGS: [EncoderConfig; ENCODERS] = [EncoderConfig {
+    resolution: Resolution::Full,
+    push: Some((1, 13)),
+}];
+
+// layers
+pub const BASE_LAYER: usize = 0;
+pub const FN_LAYER: usize = 1;
+
+// ? what a key in the matrix does when it is pressed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    // does nothing - used for positions without a switch
+    No,
+    // falls through to the next active layer below
+    Trans,
+    // normal keyboard key
+    Key(Keyboard),
+    // key pressed with a modifier held (modifier, key) - e.g. ctrl + tab
+    ModKey(Keyboard, Keyboard),
+    // consumer control (media keys)
+    Consumer(Consumer),
+    // scroll the mouse wheel once (vertical - up is positive, horizontal - right is positive)
+    Wheel(i8, i8),
+    // ? layer actions
+    // layer is on while the key is held
+    Momentary(usize),
+    // layer is turned on/off each press
+    Toggle(usize),
+    // layer is on for the next key press only
+    OneShotLayer(usize),
+    // turn on this layer and turn off all others (apart from the default layer)
+    ToLayer(usize),
+    // change the default layer
+    DefaultLayer(usize),
+    // ? tap-hold actions
+    // tap for the key, hold for the modifier (modifier, key)
+    ModTap(Keyboard, Keyboard),
+    // tap for the key, hold for the layer (layer, key)
+    LayerTap(usize, Keyboard),
+    // tap, double tap or hold for different actions (index into TAP_DANCES)
+    TapDance(usize),
+    // ? other
+    // modifier applied to the next key press only, double tap to lock
+    OneShotMod(Keyboard),
+    // shift letters until the end of the word
+    CapsWord,
+    // play back a sequence of keys (index into MACROS)
+    Macro(usize),
+    // start/stop recording a dynamic macro into a slot
+    DynamicMacroRecord(usize),
+    // play back a recorded dynamic macro
+    DynamicMacroPlay(usize),
+    // show/hide the matrix diagnostics screen
+    Diagnostics,
+    // turn the matrix tester on/off - no reports are sent and only its combo works while it is on
+    MatrixTester,
+    // restart into the usb bootloader
+    Bootloader,
 }
 
-// ? 63 keys excluding fn key and consumer - fn layer
-pub fn get_fnkeys(keys: [[i32; 14]; 5]) -> [Keyboard; 63] {
-    [
-        if keys[0][0] == 1 {
-            Keyboard::Grave
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][1] == 1 {
-            Keyboard::F1
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][2] == 1 {
-            Keyboard::F2
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][3] == 1 {
-            Keyboard::F3
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][4] == 1 {
-            Keyboard::F4
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][5] == 1 {
-            Keyboard::F5
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][6] == 1 {
-            Keyboard::F6
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][7] == 1 {
-            Keyboard::F7
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][8] == 1 {
-            Keyboard::F8
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][9] == 1 {
-            Keyboard::F9
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][10] == 1 {
-            Keyboard::F10
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][11] == 1 {
-            Keyboard::F11
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][12] == 1 {
-            Keyboard::F12
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[0][13] == 1 {
-            Keyboard::DeleteForward
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][0] == 1 {
-            Keyboard::Tab
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][1] == 1 {
-            Keyboard::Q
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][2] == 1 {
-            Keyboard::W
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][3] == 1 {
-            Keyboard::E
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][4] == 1 {
-            Keyboard::R
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][5] == 1 {
-            Keyboard::T
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][6] == 1 {
-            Keyboard::Y
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][7] == 1 {
-            Keyboard::U
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][8] == 1 {
-            Keyboard::I
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][9] == 1 {
-            Keyboard::O
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][10] == 1 {
-            Keyboard::P
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][11] == 1 {
-            Keyboard::LeftBrace
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[1][12] == 1 {
-            Keyboard::RightBrace
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][0] == 1 {
-            Keyboard::CapsLock
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][1] == 1 {
-            Keyboard::A
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][2] == 1 {
-            Keyboard::S
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][3] == 1 {
-            Keyboard::D
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][4] == 1 {
-            Keyboard::F
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][5] == 1 {
-            Keyboard::G
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][6] == 1 {
-            Keyboard::H
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][7] == 1 {
-            Keyboard::J
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][8] == 1 {
-            Keyboard::K
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][9] == 1 {
-            Keyboard::L
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][10] == 1 {
-            Keyboard::Semicolon
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][11] == 1 {
-            Keyboard::Apostrophe
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][12] == 1 {
-            Keyboard::ReturnEnter
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[2][13] == 1 {
-            Keyboard::DeleteForward
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][0] == 1 {
-            Keyboard::LeftShift
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][1] == 1 {
-            Keyboard::NonUSBackslash
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][2] == 1 {
-            Keyboard::Z
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][3] == 1 {
-            Keyboard::X
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][4] == 1 {
-            Keyboard::C
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][5] == 1 {
-            Keyboard::V
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][6] == 1 {
-            Keyboard::B
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][7] == 1 {
-            Keyboard::N
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][8] == 1 {
-            Keyboard::M
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][9] == 1 {
-            Keyboard::Comma
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][10] == 1 {
-            Keyboard::Dot
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][11] == 1 {
-            Keyboard::ForwardSlash
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][12] == 1 {
-            Keyboard::NonUSHash
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[3][13] == 1 {
-            Keyboard::UpArrow
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][0] == 1 {
-            Keyboard::LeftControl
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][1] == 1 {
-            Keyboard::LeftGUI
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][2] == 1 {
-            Keyboard::LeftAlt
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][6] == 1 {
-            Keyboard::Space
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][9] == 1 {
-            Keyboard::RightAlt
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][11] == 1 {
-            Keyboard::LeftArrow
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][12] == 1 {
-            Keyboard::DownArrow
-        } else {
-            Keyboard::NoEventIndicated
-        },
-        if keys[4][13] == 1 {
-            Keyboard::RightArrow
+// shorthand so the keymap tables stay readable
+const fn k(key: Keyboard) -> Action {
+    Action::Key(key)
+}
+const fn mo(layer: usize) -> Action {
+    Action::Momentary(layer)
+}
+const NO: Action = Action::No;
+const TRNS: Action = Action::Trans;
+
+// ? what turning the encoder does - each detent presses and releases an action
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EncoderTurn {
+    pub clockwise: Action,
+    pub anticlockwise: Action,
+    // acceleration when spun quickly
+    pub curve: Curve,
+}
+
+impl EncoderTurn {
+    pub const NONE: EncoderTurn = EncoderTurn {
+        clockwise: Action::No,
+        anticlockwise: Action::No,
+        curve: NO_ACCELERATION,
+    };
+    // falls through to the next active layer below
+    pub const TRANS: EncoderTurn = EncoderTurn {
+        clockwise: Action::Trans,
+        anticlockwise: Action::Trans,
+        curve: NO_ACCELERATION,
+    };
+}
+
+// ? encoder bindings for a layer - the push itself is a key in the layer table
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EncoderBinding {
+    pub turn: EncoderTurn,
+    // turned while pushed
+    pub pushed: EncoderTurn,
+}
+
+// volume sweeps faster when spun quickly
+const VOLUME_CURVE: Curve = &[
+    Threshold {
+        interval: 30,
+        multiplier: 4,
+    },
+    Threshold {
+        interval: 80,
+        multiplier: 2,
+    },
+];
+
+// ? one [[Action; COLS]; ROWS] table and a binding for each encoder per layer, layer 0 at the bottom
+pub struct Keymap<const LAYERS: usize> {
+    pub layers: [[[Action; COLS]; ROWS]; LAYERS],
+    pub encoders: [[EncoderBinding; ENCODERS]; LAYERS],
+}
+
+// ? the keymap - remap a key by editing its entry here
+#[rustfmt::skip]
+pub static KEYMAP: Keymap<2> = Keymap {
+    layers: [
+        // normal layer
+        [
+            [k(K::Escape), k(K::Keyboard1), k(K::Keyboard2), k(K::Keyboard3), k(K::Keyboard4), k(K::Keyboard5), k(K::Keyboard6), k(K::Keyboard7), k(K::Keyboard8), k(K::Keyboard9), k(K::Keyboard0), k(K::Minus), k(K::Equal), k(K::DeleteBackspace)],
+            [k(K::Tab), k(K::Q), k(K::W), k(K::E), k(K::R), k(K::T), k(K::Y), k(K::U), k(K::I), k(K::O), k(K::P), k(K::LeftBrace), k(K::RightBrace), Action::TapDance(0)],
+            [k(K::CapsLock), k(K::A), k(K::S), k(K::D), k(K::F), k(K::G), k(K::H), k(K::J), k(K::K), k(K::L), k(K::Semicolon), k(K::Apostrophe), k(K::ReturnEnter), k(K::NonUSHash)],
+            [k(K::LeftShift), k(K::NonUSBackslash), k(K::Z), k(K::X), k(K::C), k(K::V), k(K::B), k(K::N), k(K::M), k(K::Comma), k(K::Dot), k(K::ForwardSlash), k(K::RightShift), k(K::UpArrow)],
+            [k(K::LeftControl), k(K::LeftGUI), k(K::LeftAlt), NO, NO, NO, k(K::Space), NO, NO, k(K::RightAlt), mo(FN_LAYER), k(K::LeftArrow), k(K::DownArrow), k(K::RightArrow)],
+        ],
+        // fn layer - tab for caps word, q/w record dynamic macros and a/s play them back, d shows the matrix diagnostics,
+        // the left modifiers are one-shot
+        [
+            [k(K::Grave), k(K::F1), k(K::F2), k(K::F3), k(K::F4), k(K::F5), k(K::F6), k(K::F7), k(K::F8), k(K::F9), k(K::F10), k(K::F11), k(K::F12), k(K::DeleteForward)],
+            [Action::CapsWord, Action::DynamicMacroRecord(0), Action::DynamicMacroRecord(1), TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS],
+            [TRNS, Action::DynamicMacroPlay(0), Action::DynamicMacroPlay(1), Action::Diagnostics, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, k(K::DeleteForward)],
+            [Action::OneShotMod(K::LeftShift), TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, k(K::NonUSHash), TRNS],
+            [Action::OneShotMod(K::LeftControl), Action::OneShotMod(K::LeftGUI), Action::OneShotMod(K::LeftAlt), TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS, TRNS],
+        ],
+    ],
+    encoders: [
+        // normal layer - volume, pushed for next/previous track
+        [
+            EncoderBinding {
+                turn: EncoderTurn { clockwise: Action::Consumer(Consumer::VolumeIncrement), anticlockwise: Action::Consumer(Consumer::VolumeDecrement), curve: VOLUME_CURVE },
+                pushed: EncoderTurn { clockwise: Action::Consumer(Consumer::ScanNextTrack), anticlockwise: Action::Consumer(Consumer::ScanPreviousTrack), curve: NO_ACCELERATION },
+            },
+        ],
+        // fn layer - scroll sideways, pushed to zoom
+        [
+            EncoderBinding {
+                turn: EncoderTurn { clockwise: Action::Wheel(0, 1), anticlockwise: Action::Wheel(0, -1), curve: NO_ACCELERATION },
+                pushed: EncoderTurn { clockwise: Action::ModKey(K::LeftControl, K::Equal), anticlockwise: Action::ModKey(K::LeftControl, K::Minus), curve: NO_ACCELERATION },
+            },
+        ],
+    ],
+};
+
+// ? tap-hold timing and options
+pub const TAP_HOLD_CONFIG: TapHoldConfig = TapHoldConfig {
+    tapping_term: 200,
+    permissive_hold: false,
+    hold_on_other_key_press: false,
+    quick_tap_term: 120,
+    retro_tapping: false,
+};
+
+// ? combos - keys pressed together within the timeout
+pub static COMBOS: [Combo; 2] = [
+    // esc + backspace + fn to flash new firmware
+    Combo {
+        keys: &[(0, 0), (0, 13), (4, 10)],
+        action: Action::Bootloader,
+        timeout: 50,
+        layers: 0,
+    },
+    // esc + enter + fn to test the matrix after soldering
+    Combo {
+        keys: &[(0, 0), (2, 12), (4, 10)],
+        action: Action::MatrixTester,
+        timeout: 50,
+        layers: 0,
+    },
+];
+
+// ? tap dances - tap, double tap and hold actions
+pub static TAP_DANCES: [TapDanceDef; 1] = [
+    // rotary encoder push
+    TapDanceDef {
+        tap: Action::Consumer(Consumer::PlayPause),
+        double_tap: Action::Consumer(Consumer::Mute),
+        hold: Action::Consumer(Consumer::Stop),
+        term: 250,
+    },
+];
+
+// ? macros - played back one report at a time
+pub static MACROS: [&[MacroStep]; 1] = [
+    // select all and copy
+    &[
+        MacroStep::Press(Keyboard::LeftControl),
+        MacroStep::Tap(Keyboard::A),
+        MacroStep::Tap(Keyboard::C),
+        MacroStep::Release(Keyboard::LeftControl),
+    ],
+];
+
+// ? keys to send in a single NKRO report
+pub struct KeyReport {
+    keys: [Keyboard; ROWS * COLS],
+    len: usize,
+}
+
+impl KeyReport {
+    pub fn new() -> Self {
+        KeyReport {
+            keys: [Keyboard::NoEventIndicated; ROWS * COLS],
+            len: 0,
+        }
+    }
+
+    // add a key to the report, duplicates and keys past the report size are ignored
+    pub fn push(&mut self, key: Keyboard) {
+        if self.len < self.keys.len() && !self.keys().contains(&key) {
+            self.keys[self.len] = key;
+            self.len += 1;
+        }
+    }
+
+    pub fn keys(&self) -> &[Keyboard] {
+        &self.keys[..self.len]
+    }
+}
+
+impl Default for KeyReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const LAYERS: usize> Keymap<LAYERS> {
+    // ? the action for a key position - checks active layers from the top down, skipping transparent keys
+    pub fn resolve(&self, layers: &LayerState, row: usize, col: usize) -> Action {
+        for layer in layers.iter().filter(|layer| *layer < LAYERS) {
+            let action = self.layers[layer][row][col];
+            if action != Action::Trans {
+                return action;
+            }
+        }
+        Action::No
+    }
+
+    // ? the binding of an encoder for the active layers, pushed - turned while the encoder is pushed
+    pub fn resolve_encoder(
+        &self,
+        layers: &LayerState,
+        encoder: usize,
+        pushed: bool,
+    ) -> EncoderTurn {
+        for layer in layers.iter().filter(|layer| *layer < LAYERS) {
+            let binding = self.encoders[layer][encoder];
+            let turn = if pushed { binding.pushed } else { binding.turn };
+            if turn != EncoderTurn::TRANS {
+                return turn;
+            }
+        }
+        EncoderTurn::NONE
+    }
+}
+
+// ? which report an encoder step is sent in
+fn is_key(action: &Action) -> bool {
+    matches!(action, Action::Key(_) | Action::ModKey(_, _))
+}
+fn is_consumer(action: &Action) -> bool {
+    matches!(action, Action::Consumer(_))
+}
+fn is_wheel(action: &Action) -> bool {
+    matches!(action, Action::Wheel(_, _))
+}
+
+// ? turns key events into reports, keeping track of layers and tap-hold keys between polls
+// each key remembers the action it was pressed with, so a layer change while it is held
+// (e.g. releasing fn before F1) doesn't change its keycode until it is released
+pub struct KeyResolver<const LAYERS: usize> {
+    keymap: &'static Keymap<LAYERS>,
+    pub layers: LayerState,
+    tap_hold: TapHold,
+    tap_dances: TapDances,
+    macros: MacroPlayer,
+    dynamic_macros: DynamicMacros,
+    oneshot_mods: OneShotMods,
+    caps_word: CapsWord,
+    held: [[Action; COLS]; ROWS],
+    // pressed since the last report was sent - a quick release still has to be reported once
+    fresh: [[bool; COLS]; ROWS],
+    // actions of combos currently held down
+    combos: [Action; MAX_COMBOS],
+    combos_fresh: [bool; MAX_COMBOS],
+    // keys that were pressed and released before being reported, sent in the next report only
+    taps: Vec<Keyboard, 8>,
+    // consumer codes released before being reported, sent in the next consumer report only
+    consumer_taps: Vec<Consumer, 4>,
+    // wheel scrolled since the last mouse report (vertical, horizontal)
+    wheel: (i8, i8),
+    // steps of each encoder waiting to be sent
+    encoders: [Rotation; ENCODERS],
+    // matrix diagnostics screen shown, toggled by the diagnostics action
+    pub diagnostics: bool,
+    // matrix tester on, toggled by the matrix tester action or the host
+    pub matrix_tester: bool,
+    // set when the bootloader action is pressed
+    pub bootloader: bool,
+    // time of the latest event or tick (ms)
+    now: u32,
+}
+
+impl<const LAYERS: usize> KeyResolver<LAYERS> {
+    pub const fn new(keymap: &'static Keymap<LAYERS>, default_layer: usize) -> Self {
+        KeyResolver {
+            keymap,
+            layers: LayerState::new(default_layer),
+            tap_hold: TapHold::new(TAP_HOLD_CONFIG),
+            tap_dances: TapDances::new(&TAP_DANCES),
+            macros: MacroPlayer::new(),
+            dynamic_macros: DynamicMacros::new(),
+            oneshot_mods: OneShotMods::new(),
+            caps_word: CapsWord::new(),
+            held: [[Action::No; COLS]; ROWS],
+            fresh: [[false; COLS]; ROWS],
+            combos: [Action::No; MAX_COMBOS],
+            combos_fresh: [false; MAX_COMBOS],
+            taps: Vec::new(),
+            consumer_taps: Vec::new(),
+            wheel: (0, 0),
+            encoders: [Rotation::new(); ENCODERS],
+            diagnostics: false,
+            matrix_tester: false,
+            bootloader: false,
+            now: 0,
+        }
+    }
+
+    // ? handle a key press or release
+    pub fn event(&mut self, event: KeyEvent) {
+        self.now = event.timestamp;
+        if self.tap_hold.is_waiting() {
+            // hold back events until the tap-hold key is decided
+            if let Some(decision) = self.tap_hold.event(event) {
+                self.decide(decision);
+            }
+        } else if event.pressed {
+            // any other press means a held tap-hold key won't retro tap
+            self.tap_hold.retro_tap(&event);
+            self.fresh[event.row][event.col] = true;
+            let action = self.keymap.resolve(&self.layers, event.row, event.col);
+            self.held[event.row][event.col] = action;
+            match action {
+                Action::ModTap(_, key) | Action::LayerTap(_, key) => {
+                    if self.tap_hold.start(&event) == Some(Decision::Tap) {
+                        // quick tap - hold the tap key down
+                        self.held[event.row][event.col] = Action::Key(key);
+                        self.press(Action::Key(key));
+                    }
+                }
+                Action::TapDance(index) => {
+                    if let Some(output) = self.tap_dances.press(index, event.timestamp) {
+                        self.tap_dance_output(output);
+                    }
+                }
+                _ => self.press(action),
+            }
+        } else {
+            let action = self.held[event.row][event.col];
+            self.held[event.row][event.col] = Action::No;
+            if let Action::TapDance(index) = action {
+                if let Some(output) = self.tap_dances.release(index, event.timestamp) {
+                    self.tap_dance_output(output);
+                }
+            }
+            self.release(action, self.fresh[event.row][event.col]);
+            if let Some(code) = self.tap_hold.retro_tap(&event) {
+                self.taps.push(code).ok();
+            }
+        }
+    }
+
+    // ? handle an event coming out of the combo engine
+    // while the matrix tester is on only its own combo gets through, apart from releases of keys held before it
+    pub fn combo_event(&mut self, event: ComboEvent) {
+        match event {
+            ComboEvent::Key(event) if self.matrix_tester && event.pressed => {}
+            ComboEvent::Combo {
+                action,
+                pressed: true,
+                ..
+            } if self.matrix_tester && action != Action::MatrixTester => {}
+            ComboEvent::Key(event) => self.event(event),
+            ComboEvent::Combo {
+                index,
+                action,
+                pressed: true,
+            } => {
+                self.combos[index] = action;
+                self.combos_fresh[index] = true;
+                self.press(action);
+            }
+            ComboEvent::Combo { index, action, .. } => {
+                self.combos[index] = Action::No;
+                self.release(action, self.combos_fresh[index]);
+            }
+        }
+    }
+
+    // ? stop a held tap dance key from sending anything (e.g. the encoder push when it is rotated)
+    pub fn cancel_key(&mut self, row: usize, col: usize) {
+        if let Action::TapDance(index) = self.held[row][col] {
+            if let Some(output) = self.tap_dances.cancel(index) {
+                self.tap_dance_output(output);
+            }
+        }
+    }
+
+    // ? a detent of an encoder (1 clockwise, -1 anticlockwise), pushed - turned while the encoder is pushed
+    // turns are ignored while the matrix tester is on
+    pub fn encoder_turn(&mut self, encoder: usize, step: i8, pushed: bool) {
+        if self.matrix_tester {
+            return;
+        }
+        let turn = self.keymap.resolve_encoder(&self.layers, encoder, pushed);
+        self.encoders[encoder].turn(step, turn, self.now);
+    }
+
+    // ? no encoder steps waiting to be sent
+    pub fn is_encoder_idle(&self) -> bool {
+        self.encoders.iter().all(|encoder| encoder.is_idle())
+    }
+
+    // actions of the next encoder steps
+    fn encoder_steps(&self) -> impl Iterator<Item = Action> + '_ {
+        self.encoders.iter().filter_map(|encoder| encoder.next())
+    }
+
+    // ? check timers, call every loop with the current time in ms
+    pub fn tick(&mut self, now: u32) {
+        self.now = now;
+        // encoder steps that aren't sent in a report (e.g. layer changes) are done straight away
+        for index in 0..ENCODERS {
+            while let Some(action) = self.encoders[index].next() {
+                if is_key(&action) || is_consumer(&action) || is_wheel(&action) {
+                    break;
+                }
+                self.press(action);
+                self.release(action, false);
+                self.encoders[index].skip();
+            }
+        }
+        self.oneshot_mods.tick(now);
+        self.caps_word.tick(now);
+        if let Some(decision) = self.tap_hold.tick(now) {
+            self.decide(decision);
+        }
+        for output in self.tap_dances.tick(now) {
+            self.tap_dance_output(output);
+        }
+        let steps = match self.macros.playing() {
+            Some(MacroSource::Static(index)) => MACROS[index],
+            Some(MacroSource::Dynamic(slot)) => self.dynamic_macros.steps(slot),
+            None => &[],
+        };
+        self.macros.tick(now, steps);
+    }
+
+    // ? a dynamic macro is being recorded, for the display
+    pub fn is_recording(&self) -> bool {
+        self.dynamic_macros.is_recording()
+    }
+
+    // all actions currently held down
+    fn held_actions(&self) -> impl Iterator<Item = &Action> {
+        self.held
+            .iter()
+            .flatten()
+            .chain(self.combos.iter())
+            .chain(self.tap_dances.held())
+    }
+
+    // ? the report for the keys currently held
+    pub fn report(&self) -> KeyReport {
+        let mut report = KeyReport::new();
+        for action in self.held_actions().copied().chain(self.encoder_steps()) {
+            match action {
+                Action::Key(code) => report.push(code),
+                Action::ModKey(modifier, code) => {
+                    report.push(modifier);
+                    report.push(code);
+                }
+                _ => {}
+            }
+        }
+        for code in self.taps.iter().chain(self.macros.keys()) {
+            report.push(*code);
+        }
+        for code in self.oneshot_mods.keys() {
+            report.push(code);
+        }
+        if self.caps_word.shift() {
+            report.push(Keyboard::LeftShift);
+        }
+        report
+    }
+
+    // ? call once a report has been sent, quick taps have now been seen by the host
+    pub fn report_sent(&mut self) {
+        self.fresh = [[false; COLS]; ROWS];
+        self.combos_fresh = [false; MAX_COMBOS];
+        self.taps.clear();
+        self.macros.report_sent();
+        self.oneshot_mods.report_sent();
+        self.encoder_sent(is_key);
+    }
+
+    // ? caps word is on, for the display
+    pub fn is_caps_word(&self) -> bool {
+        self.caps_word.is_active()
+    }
+
+    // ? armed and locked one-shot modifiers, for the display
+    pub fn oneshot_state(&self) -> (u8, u8) {
+        self.oneshot_mods.state()
+    }
+
+    // ? consumer codes currently held, for the consumer report
+    pub fn consumer_codes(&self) -> [Consumer; 4] {
+        let mut codes = [Consumer::Unassigned; 4];
+        let held = self
+            .held_actions()
+            .copied()
+            .chain(self.encoder_steps())
+            .filter_map(|action| match action {
+                Action::Consumer(code) => Some(code),
+                _ => None,
+            });
+        for (slot, code) in codes
+            .iter_mut()
+            .zip(held.chain(self.consumer_taps.iter().copied()))
+        {
+            *slot = code;
+        }
+        codes
+    }
+
+    // ? call once the consumer report has been sent
+    pub fn consumer_report_sent(&mut self) {
+        self.consumer_taps.clear();
+        self.encoder_sent(is_consumer);
+    }
+
+    // ? wheel scrolled for the mouse report (vertical, horizontal)
+    pub fn wheel(&self) -> (i8, i8) {
+        self.encoder_steps()
+            .fold(self.wheel, |wheel, action| match action {
+                Action::Wheel(vertical, horizontal) => (
+                    wheel.0.saturating_add(vertical),
+                    wheel.1.saturating_add(horizontal),
+                ),
+                _ => wheel,
+            })
+    }
+
+    // ? call once the mouse report has been sent
+    pub fn wheel_report_sent(&mut self) {
+        self.wheel = (0, 0);
+        self.encoder_sent(is_wheel);
+    }
+
+    // encoder steps sent in a report of this kind have been pressed or released
+    fn encoder_sent(&mut self, kind: fn(&Action) -> bool) {
+        for encoder in self.encoders.iter_mut() {
+            if let Some(action) = encoder.held() {
+                if kind(&action) {
+                    encoder.released();
+                }
+            } else if let Some(action) = encoder.next() {
+                if is_wheel(&action) && kind(&action) {
+                    // the wheel is relative - the step is used up in the report it was sent in
+                    encoder.skip();
+                } else if kind(&action) {
+                    encoder.pressed();
+                }
+            }
+        }
+    }
+
+    // apply a tap or hold to the waiting tap-hold key then replay the events held back
+    fn decide(&mut self, decision: Decision) {
+        if let Some((row, col)) = self.tap_hold.waiting_key() {
+            let (tap, hold) = match self.held[row][col] {
+                Action::ModTap(modifier, key) => (key, Action::Key(modifier)),
+                Action::LayerTap(layer, key) => (key, Action::Momentary(layer)),
+                _ => (Keyboard::NoEventIndicated, Action::No),
+            };
+            let action = match decision {
+                Decision::Tap => Action::Key(tap),
+                Decision::Hold => hold,
+            };
+            self.held[row][col] = action;
+            if decision == Decision::Tap {
+                // the tap key hasn't been in a report yet - a release replayed straight away still has to send it once
+                self.fresh[row][col] = true;
+            }
+            self.press(action);
+            for event in self.tap_hold.finish(decision, tap) {
+                self.event(event);
+            }
+        }
+    }
+
+    fn press(&mut self, action: Action) {
+        match action {
+            Action::Momentary(layer) => self.layers.on(layer),
+            Action::Toggle(layer) => self.layers.toggle(layer),
+            Action::OneShotLayer(layer) => self.layers.set_oneshot(layer),
+            Action::ToLayer(layer) => self.layers.to(layer),
+            Action::DefaultLayer(layer) => self.layers.set_default(layer),
+            // one-shot layer is used up by the next key, which keeps its keycode until released
+            Action::Key(code) => {
+                self.layers.clear_oneshot();
+                self.oneshot_mods.key_pressed(code);
+                self.caps_word.key_pressed(code, self.now);
+                self.dynamic_macros.record(code, true);
+            }
+            Action::ModKey(modifier, code) => {
+                self.layers.clear_oneshot();
+                self.caps_word.key_pressed(code, self.now);
+                self.dynamic_macros.record(modifier, true);
+                self.dynamic_macros.record(code, true);
+            }
+            Action::Wheel(vertical, horizontal) => {
+                self.wheel.0 = self.wheel.0.saturating_add(vertical);
+                self.wheel.1 = self.wheel.1.saturating_add(horizontal);
+            }
+            Action::OneShotMod(code) => self.oneshot_mods.press(code, self.now),
+            Action::CapsWord => self.caps_word.toggle(self.now),
+            // no playing macros while recording so a macro can't end up inside itself
+            Action::Macro(index) if !self.is_recording() => {
+                self.macros.play(MacroSource::Static(index))
+            }
+            Action::DynamicMacroPlay(slot) if !self.is_recording() => {
+                self.macros.play(MacroSource::Dynamic(slot))
+            }
+            Action::DynamicMacroRecord(slot) if self.macros.playing().is_none() => {
+                self.dynamic_macros.toggle_recording(slot)
+            }
+            Action::Diagnostics => self.diagnostics = !self.diagnostics,
+            Action::MatrixTester => self.matrix_tester = !self.matrix_tester,
+            Action::Bootloader => self.bootloader = true,
+            _ => {}
+        }
+    }
+
+    // fresh - pressed since the last report, so it still has to be reported once
+    fn release(&mut self, action: Action, fresh: bool) {
+        match action {
+            Action::Momentary(layer) => self.layers.off(layer),
+            Action::OneShotMod(code) => self.oneshot_mods.release(code),
+            Action::Key(code) => {
+                self.dynamic_macros.record(code, false);
+                if fresh {
+                    self.taps.push(code).ok();
+                }
+            }
+            Action::ModKey(modifier, code) => {
+                self.dynamic_macros.record(code, false);
+                self.dynamic_macros.record(modifier, false);
+                if fresh {
+                    self.taps.push(modifier).ok();
+                    self.taps.push(code).ok();
+                }
+            }
+            Action::Consumer(code) => {
+                self.consumer_taps.push(code).ok();
+            }
+            _ => {}
+        }
+    }
+
+    fn tap_dance_output(&mut self, (action, pressed): (Action, bool)) {
+        if pressed {
+            self.press(action);
         } else {
-            Keyboard::NoEventIndicated
-        },
-    ]
+            self.release(action, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINDING: EncoderBinding = EncoderBinding {
+        turn: EncoderTurn {
+            clockwise: Action::Wheel(1, 0),
+            anticlockwise: Action::Wheel(-1, 0),
+            curve: NO_ACCELERATION,
+        },
+        pushed: EncoderTurn::NONE,
+    };
+
+    // caps as esc / ctrl, a key to press with it and the encoder scrolling
+    static TEST_KEYMAP: Keymap<1> = {
+        let mut layer = [[NO; COLS]; ROWS];
+        layer[2][0] = Action::ModTap(K::LeftControl, K::Escape);
+        layer[2][1] = k(K::A);
+        Keymap {
+            layers: [layer],
+            encoders: [[BINDING; ENCODERS]],
+        }
+    };
+
+    // reports sent every ms from `from` up to `to`, with the keys of each
+    fn run(resolver: &mut KeyResolver<1>, from: u32, to: u32) -> std::vec::Vec<KeyReport> {
+        let mut reports = std::vec::Vec::new();
+        for now in from..to {
+            resolver.tick(now);
+            reports.push(resolver.report());
+            resolver.report_sent();
+        }
+        reports
+    }
+
+    fn sent(reports: &[KeyReport], key: Keyboard) -> usize {
+        reports.iter().filter(|r| r.keys().contains(&key)).count()
+    }
+
+    #[test]
+    fn tap_sends_the_tap_key_once() {
+        let mut resolver = KeyResolver::new(&TEST_KEYMAP, 0);
+        resolver.event(KeyEvent::test(2, 0, true, 0));
+        let mut reports = run(&mut resolver, 0, 100);
+        resolver.event(KeyEvent::test(2, 0, false, 100));
+        reports.extend(run(&mut resolver, 100, 400));
+        assert_eq!(sent(&reports, K::Escape), 1);
+        assert_eq!(sent(&reports, K::LeftControl), 0);
+    }
+
+    #[test]
+    fn hold_sends_the_modifier_with_the_other_key() {
+        let mut resolver = KeyResolver::new(&TEST_KEYMAP, 0);
+        resolver.event(KeyEvent::test(2, 0, true, 0));
+        let mut reports = run(&mut resolver, 0, 250);
+        resolver.event(KeyEvent::test(2, 1, true, 250));
+        reports.extend(run(&mut resolver, 250, 260));
+        let with_a: std::vec::Vec<_> = reports
+            .iter()
+            .filter(|r| r.keys().contains(&K::A))
+            .collect();
+        assert!(!with_a.is_empty());
+        assert!(with_a.iter().all(|r| r.keys().contains(&K::LeftControl)));
+        assert_eq!(sent(&reports, K::Escape), 0);
+    }
+
+    #[test]
+    fn matrix_tester_only_takes_its_own_combo() {
+        let mut resolver = KeyResolver::new(&TEST_KEYMAP, 0);
+        let combo = |index: usize, pressed: bool| ComboEvent::Combo {
+            index,
+            action: COMBOS[index].action,
+            pressed,
+        };
+        // a held before the tester is turned on
+        resolver.combo_event(ComboEvent::Key(KeyEvent::test(2, 1, true, 0)));
+        resolver.combo_event(combo(1, true));
+        resolver.combo_event(combo(1, false));
+        assert!(resolver.matrix_tester);
+        assert_eq!(sent(&run(&mut resolver, 0, 10), K::A), 10);
+        // is still released, but nothing else gets through
+        resolver.combo_event(ComboEvent::Key(KeyEvent::test(2, 1, false, 10)));
+        resolver.combo_event(ComboEvent::Key(KeyEvent::test(2, 0, true, 10)));
+        resolver.combo_event(combo(0, true));
+        resolver.encoder_turn(0, 1, false);
+        let reports = run(&mut resolver, 10, 300);
+        assert!(reports.iter().all(|r| r.keys().is_empty()));
+        assert!(!resolver.bootloader);
+        assert_eq!(resolver.wheel(), (0, 0));
+        resolver.combo_event(combo(0, false));
+        resolver.combo_event(ComboEvent::Key(KeyEvent::test(2, 0, false, 300)));
+        // and the combo turns it off again
+        resolver.combo_event(combo(1, true));
+        resolver.combo_event(combo(1, false));
+        assert!(!resolver.matrix_tester);
+        resolver.combo_event(ComboEvent::Key(KeyEvent::test(2, 1, true, 310)));
+        assert_eq!(sent(&run(&mut resolver, 310, 320), K::A), 10);
+    }
+
+    #[test]
+    fn wheel_steps_go_in_every_mouse_report() {
+        let mut resolver = KeyResolver::new(&TEST_KEYMAP, 0);
+        for now in 0..3 {
+            resolver.encoder_turn(0, 1, false);
+            resolver.tick(now);
+        }
+        let mut wheel = std::vec::Vec::new();
+        for _ in 0..4 {
+            wheel.push(resolver.wheel());
+            resolver.wheel_report_sent();
+        }
+        assert_eq!(wheel, [(1, 0), (1, 0), (1, 0), (0, 0)]);
+        assert!(resolver.is_encoder_idle());
+    }
 }
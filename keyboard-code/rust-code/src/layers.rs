@@ -0,0 +1,80 @@
+// Layer stack - which keymap layers are currently active
+
+// ? active layers are kept as a bitmask, so up to 32 layers
+pub const MAX_LAYERS: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LayerState {
+    // layer used when nothing else is active
+    default: usize,
+    // bit n set = layer n active on top of the default layer
+    active: u32,
+    // layer armed by a one-shot layer key, cleared after the next key
+    oneshot: Option<usize>,
+}
+
+impl LayerState {
+    pub const fn new(default: usize) -> Self {
+        LayerState {
+            default,
+            active: 0,
+            oneshot: None,
+        }
+    }
+
+    pub fn default_layer(&self) -> usize {
+        self.default
+    }
+
+    pub fn is_active(&self, layer: usize) -> bool {
+        layer == self.default || self.active & (1 << layer) != 0
+    }
+
+    // ? iterate active layers from highest to lowest, the default layer included
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_LAYERS)
+            .rev()
+            .filter(move |layer| self.is_active(*layer))
+    }
+
+    pub fn on(&mut self, layer: usize) {
+        self.active |= 1 << layer;
+    }
+
+    pub fn off(&mut self, layer: usize) {
+        self.active &= !(1 << layer);
+    }
+
+    pub fn toggle(&mut self, layer: usize) {
+        self.active ^= 1 << layer;
+    }
+
+    // turn on only this layer, everything else apart from the default layer is turned off
+    pub fn to(&mut self, layer: usize) {
+        self.active = 0;
+        self.oneshot = None;
+        if layer != self.default {
+            self.on(layer);
+        }
+    }
+
+    pub fn set_default(&mut self, layer: usize) {
+        self.default = layer;
+    }
+
+    // ? one-shot layer - active until the next key is pressed
+    pub fn set_oneshot(&mut self, layer: usize) {
+        self.oneshot = Some(layer);
+        self.on(layer);
+    }
+
+    pub fn oneshot(&self) -> Option<usize> {
+        self.oneshot
+    }
+
+    pub fn clear_oneshot(&mut self) {
+        if let Some(layer) = self.oneshot.take() {
+            self.off(layer);
+        }
+    }
+}
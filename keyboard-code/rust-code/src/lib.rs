@@ -0,0 +1,21 @@
+// Keyboard logic that doesn't touch the hardware - a library so it can be tested on the host
+// cargo test --lib --target x86_64-unknown-linux-gnu
+#![cfg_attr(not(test), no_std)]
+
+pub mod acceleration;
+pub mod caps_word;
+pub mod combos;
+pub mod debounce;
+pub mod diagnostics;
+pub mod dynamic_macro;
+pub mod encoder;
+pub mod events;
+pub mod key_state;
+pub mod keys;
+pub mod layers;
+pub mod macros;
+pub mod matrix;
+pub mod oneshot;
+pub mod quadrature;
+pub mod tap_dance;
+pub mod tap_hold;
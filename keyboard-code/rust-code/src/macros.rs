@@ -0,0 +1,304 @@
+// Macros - sequences of key presses, delays and text played back without blocking the main loop
+
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MacroStep {
+    Press(Keyboard),
+    Release(Keyboard),
+    // press then release in the next report
+    Tap(Keyboard),
+    // wait this long (ms) before the next step
+    Delay(u32),
+    // type out an ascii string
+    Text(&'static str),
+}
+
+// ? where the steps of the playing macro come from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MacroSource {
+    // index into the static macro list
+    Static(usize),
+    // dynamic macro slot recorded on the keyboard
+    Dynamic(usize),
+}
+
+pub struct MacroPlayer {
+    step: usize,
+    // position in a text step
+    char_index: usize,
+    // keys held down by the macro, included in every report
+    held: Vec<Keyboard, 8>,
+    // keys pressed by the last tap, released in the next report
+    tap: Vec<Keyboard, 2>,
+    // the last change has been sent to the host
+    sent: bool,
+    // time (ms) a delay step finishes
+    wait_until: Option<u32>,
+    playing: Option<MacroSource>,
+}
+
+impl MacroPlayer {
+    pub const fn new() -> Self {
+        MacroPlayer {
+            step: 0,
+            char_index: 0,
+            held: Vec::new(),
+            tap: Vec::new(),
+            sent: false,
+            wait_until: None,
+            playing: None,
+        }
+    }
+
+    // ? the macro being played, if any
+    pub fn playing(&self) -> Option<MacroSource> {
+        self.playing
+    }
+
+    // ? start playing a macro, ignored if one is already playing
+    pub fn play(&mut self, source: MacroSource) {
+        if self.playing.is_none() {
+            self.step = 0;
+            self.char_index = 0;
+            self.held.clear();
+            self.tap.clear();
+            self.sent = true;
+            self.wait_until = None;
+            self.playing = Some(source);
+        }
+    }
+
+    // ? keys the macro is holding down right now
+    pub fn keys(&self) -> impl Iterator<Item = &Keyboard> {
+        self.held.iter()
+    }
+
+    // ? call once a report has been sent - the next step waits until the host has seen this one
+    pub fn report_sent(&mut self) {
+        self.sent = true;
+    }
+
+    // ? move on one step of the playing macro, call every loop with the current time in ms
+    pub fn tick(&mut self, now: u32, steps: &[MacroStep]) {
+        if self.playing.is_none() || !self.sent {
+            return;
+        }
+        if let Some(wait_until) = self.wait_until {
+            if (now.wrapping_sub(wait_until) as i32) < 0 {
+                return;
+            }
+            self.wait_until = None;
+        }
+        self.sent = false;
+
+        // release the last tap first, it takes a report of its own
+        if !self.tap.is_empty() {
+            for key in self.tap.iter() {
+                if let Some(i) = self.held.iter().position(|k| k == key) {
+                    self.held.swap_remove(i);
+                }
+            }
+            self.tap.clear();
+            return;
+        }
+
+        while let Some(step) = steps.get(self.step) {
+            match *step {
+                MacroStep::Press(key) => {
+                    self.held.push(key).ok();
+                }
+                MacroStep::Release(key) => {
+                    if let Some(i) = self.held.iter().position(|k| *k == key) {
+                        self.held.swap_remove(i);
+                    }
+                }
+                MacroStep::Tap(key) => self.tap_keys(&[key]),
+                MacroStep::Delay(ms) => {
+                    self.wait_until = Some(now.wrapping_add(ms));
+                }
+                MacroStep::Text(text) => {
+                    if let Some(c) = text.as_bytes().get(self.char_index) {
+                        self.char_index += 1;
+                        match ascii_to_keys(*c) {
+                            Some((key, true)) => self.tap_keys(&[Keyboard::LeftShift, key]),
+                            Some((key, false)) => self.tap_keys(&[key]),
+                            // characters that can't be typed are skipped
+                            None => self.sent = true,
+                        }
+                        return;
+                    }
+                    // end of the text, carry on to the next step
+                    self.char_index = 0;
+                    self.step += 1;
+                    continue;
+                }
+            }
+            self.step += 1;
+            return;
+        }
+
+        // finished - let go of anything still held
+        if self.held.is_empty() {
+            self.playing = None;
+        }
+        self.held.clear();
+    }
+
+    fn tap_keys(&mut self, keys: &[Keyboard]) {
+        for key in keys {
+            self.held.push(*key).ok();
+            self.tap.push(*key).ok();
+        }
+    }
+}
+
+impl Default for MacroPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ? ascii character to key and whether shift is needed - for a UK host layout, same as the keymap
+pub fn ascii_to_keys(c: u8) -> Option<(Keyboard, bool)> {
+    Some(match c {
+        b'a'..=b'z' => (letter(c - b'a'), false),
+        b'A'..=b'Z' => (letter(c - b'A'), true),
+        b'1'..=b'9' => (number(c - b'1'), false),
+        b'0' => (Keyboard::Keyboard0, false),
+        b'\n' => (Keyboard::ReturnEnter, false),
+        b'\t' => (Keyboard::Tab, false),
+        b' ' => (Keyboard::Space, false),
+        b'-' => (Keyboard::Minus, false),
+        b'_' => (Keyboard::Minus, true),
+        b'=' => (Keyboard::Equal, false),
+        b'+' => (Keyboard::Equal, true),
+        b'[' => (Keyboard::LeftBrace, false),
+        b'{' => (Keyboard::LeftBrace, true),
+        b']' => (Keyboard::RightBrace, false),
+        b'}' => (Keyboard::RightBrace, true),
+        b';' => (Keyboard::Semicolon, false),
+        b':' => (Keyboard::Semicolon, true),
+        b'\'' => (Keyboard::Apostrophe, false),
+        b'@' => (Keyboard::Apostrophe, true),
+        b'#' => (Keyboard::NonUSHash, false),
+        b'~' => (Keyboard::NonUSHash, true),
+        b'\\' => (Keyboard::NonUSBackslash, false),
+        b'|' => (Keyboard::NonUSBackslash, true),
+        b'`' => (Keyboard::Grave, false),
+        b',' => (Keyboard::Comma, false),
+        b'<' => (Keyboard::Comma, true),
+        b'.' => (Keyboard::Dot, false),
+        b'>' => (Keyboard::Dot, true),
+        b'/' => (Keyboard::ForwardSlash, false),
+        b'?' => (Keyboard::ForwardSlash, true),
+        b'!' => (Keyboard::Keyboard1, true),
+        b'"' => (Keyboard::Keyboard2, true),
+        b'$' => (Keyboard::Keyboard4, true),
+        b'%' => (Keyboard::Keyboard5, true),
+        b'^' => (Keyboard::Keyboard6, true),
+        b'&' => (Keyboard::Keyboard7, true),
+        b'*' => (Keyboard::Keyboard8, true),
+        b'(' => (Keyboard::Keyboard9, true),
+        b')' => (Keyboard::Keyboard0, true),
+        _ => return None,
+    })
+}
+
+// letter keys and number keys are in order in the usb hid keyboard page
+fn letter(offset: u8) -> Keyboard {
+    Keyboard::from(Keyboard::A as u8 + offset)
+}
+
+fn number(offset: u8) -> Keyboard {
+    Keyboard::from(Keyboard::Keyboard1 as u8 + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Keyboard as K;
+
+    // play a macro sending a report every ms, returns (time, keys) for every report that changed
+    fn reports(steps: &[MacroStep]) -> std::vec::Vec<(u32, std::vec::Vec<Keyboard>)> {
+        let mut player = MacroPlayer::new();
+        player.play(MacroSource::Static(0));
+        let mut reports = std::vec::Vec::new();
+        let mut last = std::vec::Vec::new();
+        for now in 0..1000 {
+            player.tick(now, steps);
+            let mut keys: std::vec::Vec<Keyboard> = player.keys().copied().collect();
+            keys.sort_by_key(|key| *key as u8);
+            if keys != last {
+                reports.push((now, keys.clone()));
+                last = keys;
+            }
+            player.report_sent();
+            if player.playing().is_none() {
+                break;
+            }
+        }
+        assert_eq!(player.playing(), None);
+        reports
+    }
+
+    fn keys(reports: &[(u32, std::vec::Vec<Keyboard>)]) -> std::vec::Vec<std::vec::Vec<Keyboard>> {
+        reports.iter().map(|(_, keys)| keys.clone()).collect()
+    }
+
+    #[test]
+    fn taps_are_released_in_their_own_report() {
+        let reports = reports(&[MacroStep::Tap(K::A), MacroStep::Tap(K::A)]);
+        assert_eq!(keys(&reports), [vec![K::A], vec![], vec![K::A], vec![]]);
+    }
+
+    #[test]
+    fn text_is_typed_with_shift() {
+        let reports = reports(&[MacroStep::Text("aB!")]);
+        assert_eq!(
+            keys(&reports),
+            [
+                vec![K::A],
+                vec![],
+                vec![K::B, K::LeftShift],
+                vec![],
+                vec![K::Keyboard1, K::LeftShift],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn delay_holds_back_the_next_step() {
+        let reports = reports(&[
+            MacroStep::Tap(K::A),
+            MacroStep::Delay(50),
+            MacroStep::Tap(K::B),
+        ]);
+        assert_eq!(keys(&reports), [vec![K::A], vec![], vec![K::B], vec![]]);
+        let released = reports[1].0;
+        let pressed = reports[2].0;
+        assert!(pressed - released >= 50);
+    }
+
+    #[test]
+    fn keys_still_held_are_released_at_the_end() {
+        let reports = reports(&[MacroStep::Press(K::LeftControl), MacroStep::Tap(K::C)]);
+        assert_eq!(
+            keys(&reports),
+            [
+                vec![K::LeftControl],
+                vec![K::C, K::LeftControl],
+                vec![K::LeftControl],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn untypeable_characters_are_skipped() {
+        let reports = reports(&[MacroStep::Text("a\u{7f}b")]);
+        assert_eq!(keys(&reports), [vec![K::A], vec![], vec![K::B], vec![]]);
+    }
+}
@@ -7,15 +7,16 @@
 #![no_main]
 
 // core
+use core::fmt::Write;
 use cortex_m::delay;
 use cortex_m_rt::{entry, exception, ExceptionFrame};
-use embedded_hal::digital::v2::*;
 use embedded_hal::prelude::*;
 use embedded_hal::timer::Cancel;
 use fugit::{ExtU32, RateExtU32};
 use panic_halt as _;
-use rp2040_hal::gpio::DynPin;
 use rp2040_hal::multicore::{Multicore, Stack};
+#[cfg(not(feature = "software-scanner"))]
+use rp2040_hal::pio::PIOExt;
 use rp_pico::{
     hal,
     hal::clocks::{Clock, SystemClock},
@@ -24,12 +25,13 @@ use rp_pico::{
 // display
 use display_interface_i2c::I2CInterface;
 use embedded_graphics::{
-    mono_font::{ascii::FONT_6X12, MonoTextStyle},
+    mono_font::{ascii::FONT_6X12, MonoTextStyle, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
     prelude::*,
     primitives::{Circle, Polyline, PrimitiveStyle, Rectangle, RoundedRectangle},
     text::{Alignment, Text},
 };
+use heapless::String;
 use ssd1309::{prelude::*, Builder};
 // usb hid
 use usb_device::{class_prelude::*, prelude::*};
@@ -37,12 +39,30 @@ use usbd_human_interface_device::device::consumer::{
     ConsumerControlInterface, MultipleConsumerReport,
 };
 use usbd_human_interface_device::device::keyboard::NKROBootKeyboardInterface;
-use usbd_human_interface_device::page::Consumer;
+use usbd_human_interface_device::device::mouse::{WheelMouseInterface, WheelMouseReport};
+use usbd_human_interface_device::hid_class::prelude::{RawInterface, RawInterfaceBuilder};
 use usbd_human_interface_device::prelude::*;
 
 // src
-pub mod consumer;
-pub mod keys;
+use idle::Wake;
+use pins::EncoderPin;
+#[cfg(feature = "software-scanner")]
+use pins::MatrixPin;
+#[cfg(not(feature = "software-scanner"))]
+use pio_matrix::PioMatrix;
+use rust_code::debounce::{DebounceAlgorithm, Debouncer};
+use rust_code::diagnostics::{
+    MatrixStats, DIAGNOSTICS_REPORT_DESCRIPTOR, MATRIX_TESTER, REPORT_SIZE,
+};
+use rust_code::encoder::Encoder;
+use rust_code::key_state::KeyState;
+#[cfg(feature = "software-scanner")]
+use rust_code::matrix::{DiodeDirection, Matrix};
+use rust_code::matrix::{GhostPolicy, Ghosting, ScanTiming};
+use rust_code::{combos, events, keys};
+pub mod idle;
+pub mod pins;
+pub mod pio_matrix;
 
 // declarations
 static mut CORE1_STACK: Stack<4096> = Stack::new();
@@ -50,6 +70,27 @@ const DISPLAY_ON: u32 = 0xDE;
 const DISPLAY_OFF: u32 = 0xDD;
 const CAPS_ON: u32 = 0xCC;
 const CAPS_OFF: u32 = 0xCD;
+const CAPS_WORD_ON: u32 = 0xCE;
+const CAPS_WORD_OFF: u32 = 0xCF;
+const RECORDING_ON: u32 = 0xBA;
+const RECORDING_OFF: u32 = 0xBB;
+// one-shot modifiers - armed bits in the low byte, locked bits in the next byte
+const ONESHOT_MODS: u32 = 0x1000_0000;
+const MESSAGE_TYPE: u32 = 0xF000_0000;
+// matrix diagnostics screen and its values - the value is in the bits below MESSAGE_TYPE
+const DIAGNOSTICS_ON: u32 = 0xDA;
+const DIAGNOSTICS_OFF: u32 = 0xDB;
+const DIAG_SCAN_RATE: u32 = 0x2000_0000;
+const DIAG_JITTER: u32 = 0x3000_0000;
+const DIAG_GHOSTS: u32 = 0x4000_0000;
+const DIAG_BOUNCES: u32 = 0x5000_0000;
+// key with the most chatter - row in bits 24-27, col in bits 16-23, chatter count in the low 16 bits (0 for none)
+const DIAG_CHATTER: u32 = 0x6000_0000;
+const MESSAGE_VALUE: u32 = 0x0FFF_FFFF;
+// matrix tester grid - keys pressed in a row, row in bits 16-19 and a bit per column below
+const MATRIX_TESTER_ON: u32 = 0xEA;
+const MATRIX_TESTER_OFF: u32 = 0xEB;
+const TESTER_ROW: u32 = 0x7000_0000;
 
 // ? implementing exception frame handling
 #[exception]
@@ -97,6 +138,21 @@ fn core1_task(sys_clock: &SystemClock) -> ! {
 
     // drawing variables
     let mut caps_on = false;
+    let mut caps_word = false;
+    let mut recording = false;
+    let mut oneshot_armed: u8 = 0;
+    let mut oneshot_locked: u8 = 0;
+    let mut diagnostics = false;
+    let mut diag_scan_rate = 0;
+    let mut diag_jitter = 0;
+    let mut diag_ghosts = 0;
+    let mut diag_bounces = 0;
+    let mut diag_chatter = 0;
+    let mut diag_text: String<96> = String::new();
+    let mut matrix_tester = false;
+    // keys down now and keys that have been pressed since the tester was turned on, bit per column
+    let mut tester_pressed = [0u32; 5];
+    let mut tester_ever_pressed = [0u32; 5];
 
     let disp_dim = disp.get_dimensions();
     let circle_rad: i32 = 5;
@@ -134,12 +190,98 @@ fn core1_task(sys_clock: &SystemClock) -> ! {
         text_style,
         Alignment::Center,
     );
+    let caps_word_text = Text::with_alignment(
+        "Caps Word\nON",
+        Point::new(32, 105),
+        text_style,
+        Alignment::Center,
+    );
+    let caps_word_box = RoundedRectangle::with_equal_corners(
+        Rectangle::new(Point::new(12, 52), Size::new(40, 20)),
+        Size::new(3, 3),
+    )
+    .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1));
+    let caps_word_letters =
+        Text::with_alignment("ABC", Point::new(29, 65), text_style, Alignment::Center);
+    let caps_word_cursor = Rectangle::new(Point::new(39, 65), Size::new(6, 2))
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On));
+    let mut caps_word_frame: u32 = 0;
+    let recording_dot =
+        Circle::new(Point::new(3, 3), 7).into_styled(PrimitiveStyle::with_fill(BinaryColor::On));
+    let recording_text = Text::new("REC", Point::new(13, 10), text_style);
+    let locked_text_style = MonoTextStyleBuilder::new()
+        .font(&FONT_6X12)
+        .text_color(BinaryColor::Off)
+        .background_color(BinaryColor::On)
+        .build();
+    // ctrl, shift, alt, gui - left and right shown the same
+    let oneshot_labels = ["C", "S", "A", "G"];
 
     loop {
         // todo - add more circles/shapes different sizes with some binarycolor::on and some off
         // ? draw to display
         disp.clear();
-        if !caps_on {
+        if matrix_tester {
+            //? draw the matrix tester - a cell for each key, rows across the screen and columns down it
+            // filled when pressed, outlined once it has been pressed, a dot if it never has
+            for (row, (pressed, ever_pressed)) in tester_pressed
+                .iter()
+                .zip(tester_ever_pressed.iter())
+                .enumerate()
+            {
+                for col in 0..14 {
+                    let position = Point::new(2 + 12 * row as i32, 16 + 8 * col);
+                    let cell = Rectangle::new(position, Size::new(10, 6));
+                    if pressed & (1 << col) != 0 {
+                        cell.into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                            .draw(&mut disp)
+                            .unwrap();
+                    } else if ever_pressed & (1 << col) != 0 {
+                        cell.into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                            .draw(&mut disp)
+                            .unwrap();
+                    } else {
+                        Rectangle::new(position + Point::new(4, 2), Size::new(2, 2))
+                            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                            .draw(&mut disp)
+                            .unwrap();
+                    }
+                }
+            }
+        } else if diagnostics {
+            //? draw matrix diagnostics
+            diag_text.clear();
+            write!(
+                diag_text,
+                "Matrix\n{}Hz\njit {}us\nghost {}\nbounce {}\nchatter\n",
+                diag_scan_rate, diag_jitter, diag_ghosts, diag_bounces
+            )
+            .ok();
+            if diag_chatter & 0xFFFF == 0 {
+                write!(diag_text, "none").ok();
+            } else {
+                write!(
+                    diag_text,
+                    "r{} c{} x{}",
+                    diag_chatter >> 24,
+                    (diag_chatter >> 16) & 0xFF,
+                    diag_chatter & 0xFFFF
+                )
+                .ok();
+            }
+            Text::new(&diag_text, Point::new(0, 24), text_style)
+                .draw(&mut disp)
+                .unwrap();
+        } else if caps_word {
+            //? draw caps word on - a word with a blinking cursor
+            caps_word_box.draw(&mut disp).unwrap();
+            caps_word_letters.draw(&mut disp).unwrap();
+            caps_word_text.draw(&mut disp).unwrap();
+            if caps_word_frame % 20 < 10 {
+                caps_word_cursor.draw(&mut disp).unwrap();
+            }
+            caps_word_frame = caps_word_frame.wrapping_add(1);
+        } else if !caps_on {
             // animation
             circle
                 .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
@@ -162,14 +304,31 @@ fn core1_task(sys_clock: &SystemClock) -> ! {
             caps_arrow.draw(&mut disp).unwrap();
             caps_text.draw(&mut disp).unwrap();
             caps_y_pos += caps_velocity.y;
-            if caps_y_pos > caps_max_pos {
-                caps_velocity.y *= -1;
-            } else if caps_y_pos < 0 {
+            if caps_y_pos > caps_max_pos || caps_y_pos < 0 {
                 caps_velocity.y *= -1;
             }
             caps_arrow.translate_mut(caps_velocity);
             caps_block.translate_mut(caps_velocity);
         }
+        if recording {
+            //? draw dynamic macro recording indicator
+            recording_dot.draw(&mut disp).unwrap();
+            recording_text.draw(&mut disp).unwrap();
+        }
+        //? draw one-shot modifiers, locked ones inverted
+        for (i, label) in oneshot_labels.iter().enumerate() {
+            let bits = (1 << i) | (1 << (i + 4));
+            let position = Point::new(38 + 6 * i as i32, 10);
+            if oneshot_locked & bits != 0 {
+                Text::new(label, position, locked_text_style)
+                    .draw(&mut disp)
+                    .unwrap();
+            } else if oneshot_armed & bits != 0 {
+                Text::new(label, position, text_style)
+                    .draw(&mut disp)
+                    .unwrap();
+            }
+        }
         disp.flush().unwrap();
 
         // ? read fifo
@@ -183,6 +342,46 @@ fn core1_task(sys_clock: &SystemClock) -> ! {
                 caps_on = true;
             } else if fifo_read == Some(CAPS_OFF) {
                 caps_on = false;
+            } else if fifo_read == Some(CAPS_WORD_ON) {
+                caps_word = true;
+            } else if fifo_read == Some(CAPS_WORD_OFF) {
+                caps_word = false;
+            } else if fifo_read == Some(RECORDING_ON) {
+                recording = true;
+            } else if fifo_read == Some(RECORDING_OFF) {
+                recording = false;
+            } else if fifo_read == Some(DIAGNOSTICS_ON) {
+                diagnostics = true;
+            } else if fifo_read == Some(DIAGNOSTICS_OFF) {
+                diagnostics = false;
+            } else if fifo_read == Some(MATRIX_TESTER_ON) {
+                // start again - every key has to be pressed
+                matrix_tester = true;
+                tester_pressed = [0; 5];
+                tester_ever_pressed = [0; 5];
+            } else if fifo_read == Some(MATRIX_TESTER_OFF) {
+                matrix_tester = false;
+            } else if let Some(message) = fifo_read {
+                let value = message & MESSAGE_VALUE;
+                match message & MESSAGE_TYPE {
+                    ONESHOT_MODS => {
+                        oneshot_armed = message as u8;
+                        oneshot_locked = (message >> 8) as u8;
+                    }
+                    DIAG_SCAN_RATE => diag_scan_rate = value,
+                    DIAG_JITTER => diag_jitter = value,
+                    DIAG_GHOSTS => diag_ghosts = value,
+                    DIAG_BOUNCES => diag_bounces = value,
+                    DIAG_CHATTER => diag_chatter = value,
+                    TESTER_ROW => {
+                        let row = (value >> 16) as usize & 0xF;
+                        if row < tester_pressed.len() {
+                            tester_pressed[row] = value & 0xFFFF;
+                            tester_ever_pressed[row] |= value & 0xFFFF;
+                        }
+                    }
+                    _ => {}
+                }
             }
         }
     }
@@ -208,6 +407,11 @@ fn main() -> ! {
 
     let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS);
     let mut sio = hal::Sio::new(pac.SIO);
+    // before the clocks are given to core1
+    let sys_freq = clocks.system_clock.freq().to_Hz();
+    // delay for the matrix settle time
+    #[cfg(feature = "software-scanner")]
+    let mut delay = delay::Delay::new(pac::CorePeripherals::take().unwrap().SYST, sys_freq);
 
     let pins = hal::gpio::Pins::new(
         pac.IO_BANK0,
@@ -238,6 +442,14 @@ fn main() -> ! {
             usbd_human_interface_device::device::keyboard::NKROBootKeyboardInterface::default_config(),
         )
         .add_interface(usbd_human_interface_device::device::consumer::ConsumerControlInterface::default_config())
+        // mouse wheel for the encoder
+        .add_interface(WheelMouseInterface::default_config())
+        // vendor interface for the host to read the matrix diagnostics
+        .add_interface(
+            RawInterfaceBuilder::new(DIAGNOSTICS_REPORT_DESCRIPTOR)
+                .description("Matrix Diagnostics")
+                .build(),
+        )
         .build(&usb_bus);
 
     // ? https://pid.codes
@@ -249,67 +461,121 @@ fn main() -> ! {
         .build();
 
     // ? GPIO pin and variable set up
+    // matrix scanned in software on core0
     // rows
-    let row_pins: &[&dyn InputPin<Error = core::convert::Infallible>] = &[
-        &pins.gpio20.into_pull_up_input(),
-        &pins.gpio19.into_pull_up_input(),
-        &pins.gpio18.into_pull_up_input(),
-        &pins.gpio17.into_pull_up_input(),
-        &pins.gpio16.into_pull_up_input(),
+    #[cfg(feature = "software-scanner")]
+    let row_pins = [
+        MatrixPin::new(pins.gpio20.into()),
+        MatrixPin::new(pins.gpio19.into()),
+        MatrixPin::new(pins.gpio18.into()),
+        MatrixPin::new(pins.gpio17.into()),
+        MatrixPin::new(pins.gpio16.into()),
     ];
 
     // cols
     // so we can cycle through each column to check rows, first turn them into dynpins then put in array
-    let col0: DynPin = pins.gpio13.into();
-    let col1: DynPin = pins.gpio14.into();
-    let col2: DynPin = pins.gpio15.into();
-    let col3: DynPin = pins.gpio12.into();
-    let col4: DynPin = pins.gpio11.into();
-    let col5: DynPin = pins.gpio10.into();
-    let col6: DynPin = pins.gpio9.into();
-    let col7: DynPin = pins.gpio8.into();
-    let col8: DynPin = pins.gpio2.into();
-    let col9: DynPin = pins.gpio3.into();
-    let col10: DynPin = pins.gpio4.into();
-    let col11: DynPin = pins.gpio5.into();
-    let col12: DynPin = pins.gpio6.into();
-    let col13: DynPin = pins.gpio7.into();
-
-    let mut col_pins = [
-        col0, col1, col2, col3, col4, col5, col6, col7, col8, col9, col10, col11, col12, col13,
+    #[cfg(feature = "software-scanner")]
+    let col_pins = [
+        MatrixPin::new(pins.gpio13.into()),
+        MatrixPin::new(pins.gpio14.into()),
+        MatrixPin::new(pins.gpio15.into()),
+        MatrixPin::new(pins.gpio12.into()),
+        MatrixPin::new(pins.gpio11.into()),
+        MatrixPin::new(pins.gpio10.into()),
+        MatrixPin::new(pins.gpio9.into()),
+        MatrixPin::new(pins.gpio8.into()),
+        MatrixPin::new(pins.gpio2.into()),
+        MatrixPin::new(pins.gpio3.into()),
+        MatrixPin::new(pins.gpio4.into()),
+        MatrixPin::new(pins.gpio5.into()),
+        MatrixPin::new(pins.gpio6.into()),
+        MatrixPin::new(pins.gpio7.into()),
     ];
-    // set default state of col pins to input
-    for i in 0..14 {
-        col_pins[i].into_pull_up_input();
-    }
 
-    // rotary encoder
-    let rot_a = &pins.gpio0.into_pull_up_input();
-    let rot_b = &pins.gpio1.into_pull_up_input();
-    let mut rot_a_last_state = rot_a.is_low().unwrap();
-    let mut rot_was_pressed = false;
-    let mut rot_can_push = true;
-    let mut rot_rotation_dir: i32 = 0;
-
-    // key state - 1 is pressed, 0 is released
+    // columns are driven low and rows are read
+    #[cfg(feature = "software-scanner")]
+    let mut matrix: Matrix<_, _, 5, 14> =
+        Matrix::new(row_pins, col_pins, DiodeDirection::Row2Col).unwrap();
+    // let each column settle before the rows are read (us)
+    #[cfg(feature = "software-scanner")]
+    matrix.set_settle_us(&[5]);
+
+    // matrix scanned by PIO0 and copied out by DMA - the same pins as gpio numbers in matrix order
+    #[cfg(not(feature = "software-scanner"))]
+    let mut matrix: PioMatrix<5, 14> = {
+        let _ = (
+            pins.gpio16.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio17.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio18.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio19.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio20.into_mode::<hal::gpio::FunctionPio0>(),
+        );
+        let _ = (
+            pins.gpio2.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio3.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio4.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio5.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio6.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio7.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio8.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio9.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio10.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio11.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio12.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio13.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio14.into_mode::<hal::gpio::FunctionPio0>(),
+            pins.gpio15.into_mode::<hal::gpio::FunctionPio0>(),
+        );
+        let (mut pio0, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+        // 10kHz scan, 16 cycles (about 4us) for each column to settle
+        PioMatrix::new(
+            &mut pio0,
+            sm0,
+            pac.DMA,
+            &mut pac.RESETS,
+            [20, 19, 18, 17, 16],
+            [13, 14, 15, 12, 11, 10, 9, 8, 2, 3, 4, 5, 6, 7],
+            16,
+            10_000,
+            sys_freq,
+        )
+    };
+    // the matrix is read every scan_count_down - 1kHz, the actual rate and jitter are measured
+    // the PIO scanner runs at its own fixed rate, the read only picks up its latest scan
+    let mut scan_count_down = timer.count_down();
+    scan_count_down.start(1.millis());
+    #[cfg(feature = "software-scanner")]
+    let mut scan_timing = ScanTiming::new();
+    #[cfg(not(feature = "software-scanner"))]
+    let scan_timing = ScanTiming::fixed(matrix.scan_rate());
+    // per key chatter statistics
+    let mut matrix_stats: MatrixStats<5, 14> = MatrixStats::new();
+    // reply to the host waiting to be sent
+    let mut diagnostics_reply: Option<[u8; REPORT_SIZE]> = None;
+    // ghost keys are only marked and counted per key in the diagnostics - the diodes should stop real ghosting (col1 has been seen ghosting)
+    // GhostPolicy::Block would hold back any key that completes a rectangle of pressed keys
+    let mut ghosting: Ghosting<5> = Ghosting::new(GhostPolicy::Mark);
+
+    // rotary encoders (a, b) - resolution and push switch are set in keys::ENCODER_CONFIGS
+    let mut encoders: [Encoder<EncoderPin>; keys::ENCODERS] = [Encoder::new(
+        &keys::ENCODER_CONFIGS[0],
+        EncoderPin::new(pins.gpio0.into()),
+        EncoderPin::new(pins.gpio1.into()),
+    )];
+
+    // key state - a bit per key, set when pressed
     // recording the key state should be separate from usb polling so that they can work independently
-    let mut pressed_keys: [[i32; 14]; 5] = [
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-    ];
-    // key debounce flip flop
-    let mut debounce_keys: [[i32; 14]; 5] = [
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-    ];
-    // debounce iterations until press or release is confirmed
-    let confirmed_press = 4;
+    let mut pressed_keys = KeyState::new();
+    // debounce the matrix scan - press straight away, release once it has been up for 5ms
+    let mut debouncer: Debouncer<5, 14> =
+        Debouncer::new(DebounceAlgorithm::EagerPressDeferredRelease, 5);
+
+    // keymap layers and tap-hold keys
+    let mut key_resolver = keys::KeyResolver::new(&keys::KEYMAP, keys::BASE_LAYER);
+    // combos are picked out before the keymap
+    let mut combos = combos::Combos::new(&keys::COMBOS);
+    // presses and releases from the scan, in order
+    let mut key_events = events::KeyEvents::new();
 
     // usb polling rate countdown
     let mut input_count_down = timer.count_down();
@@ -321,11 +587,29 @@ fn main() -> ! {
     let mut consumer_poll = timer.count_down();
     consumer_poll.start(1.millis());
     let mut last_consumer_report = MultipleConsumerReport::default();
+    // mouse polling rate countdown - the mouse endpoint is polled every 10ms
+    let mut mouse_poll = timer.count_down();
+    mouse_poll.start(10.millis());
 
     // display
     let mut display_turn_on = true;
     let mut display_toggled = false;
     let display_on_time = 5.minutes();
+
+    // idle sleep once the display is off - woken by a row or an encoder
+    let mut idle_wake_pins: heapless::Vec<(u8, Wake), 16> = [16, 17, 18, 19, 20]
+        .iter()
+        .map(|row| (*row, Wake::Falling))
+        .collect();
+    for encoder in encoders.iter() {
+        let (a, b) = encoder.pins();
+        idle_wake_pins
+            .extend_from_slice(&[(a.gpio(), Wake::Both), (b.gpio(), Wake::Both)])
+            .unwrap();
+    }
+    // stay awake for a while after waking so reports and the encoder are seen to
+    let mut awake_count_down = timer.count_down();
+    awake_count_down.start(50.millis());
     let mut display_off_timer = timer.count_down();
     display_off_timer.start(display_on_time);
 
@@ -333,16 +617,30 @@ fn main() -> ! {
     let mut caps_on = false;
     let mut caps_toggled = false;
 
+    // caps word - separate from the host caps lock state
+    let mut caps_word_toggled = false;
+
+    // dynamic macro recording
+    let mut recording_toggled = false;
+
+    // one-shot modifiers last sent to the display
+    let mut last_oneshot_state = (0, 0);
+
+    // matrix diagnostics screen - one value is sent each diagnostics_count_down
+    let mut diagnostics_toggled = false;
+    let mut diagnostics_count_down = timer.count_down();
+    diagnostics_count_down.start(100.millis());
+    let mut diagnostics_message = 0;
+
+    // matrix tester - rows of pressed keys last sent to the display
+    let mut matrix_tester_toggled = false;
+    let mut tester_rows = [0u32; 5];
+
     loop {
         // ? toggle on/off display if keyboard inactive for some time
         // checking keyboard activity
-        let mut keyboard_activity = 0;
-        for i in 0..14 {
-            for j in 0..5 {
-                keyboard_activity += pressed_keys[j][i];
-            }
-        }
-        keyboard_activity += rot_rotation_dir.pow(2);
+        let mut keyboard_activity = pressed_keys.count() as i32;
+        keyboard_activity += !key_resolver.is_encoder_idle() as i32;
         // reset
         if keyboard_activity > 0 {
             display_off_timer.start(display_on_time);
@@ -365,6 +663,24 @@ fn main() -> ! {
             sio.fifo.write(DISPLAY_ON);
         }
 
+        // ? idle sleep while the display is off - every column driven low so a key press wakes core0
+        if !display_turn_on
+            && display_toggled
+            && keyboard_activity == 0
+            && awake_count_down.wait().is_ok()
+        {
+            #[cfg(feature = "software-scanner")]
+            matrix.set_idle(true).unwrap();
+            #[cfg(not(feature = "software-scanner"))]
+            matrix.set_idle(true);
+            idle::sleep(&idle_wake_pins);
+            #[cfg(feature = "software-scanner")]
+            matrix.set_idle(false).unwrap();
+            #[cfg(not(feature = "software-scanner"))]
+            matrix.set_idle(false);
+            awake_count_down.start(50.millis());
+        }
+
         // ? toggle when caps_on
         // send message
         if !caps_toggled && caps_on && sio.fifo.is_write_ready() {
@@ -376,29 +692,101 @@ fn main() -> ! {
             sio.fifo.write(CAPS_OFF);
         }
 
+        // ? toggle when caps word is on
+        // send message
+        if !caps_word_toggled && key_resolver.is_caps_word() && sio.fifo.is_write_ready() {
+            caps_word_toggled = true;
+            sio.fifo.write(CAPS_WORD_ON);
+        } else if !key_resolver.is_caps_word() && caps_word_toggled && sio.fifo.is_write_ready() {
+            // reset
+            caps_word_toggled = false;
+            sio.fifo.write(CAPS_WORD_OFF);
+        }
+
+        // ? toggle when recording a dynamic macro
+        // send message
+        if !recording_toggled && key_resolver.is_recording() && sio.fifo.is_write_ready() {
+            recording_toggled = true;
+            sio.fifo.write(RECORDING_ON);
+        } else if !key_resolver.is_recording() && recording_toggled && sio.fifo.is_write_ready() {
+            // reset
+            recording_toggled = false;
+            sio.fifo.write(RECORDING_OFF);
+        }
+
+        // ? send one-shot modifiers when they change
+        let oneshot_state = key_resolver.oneshot_state();
+        if oneshot_state != last_oneshot_state && sio.fifo.is_write_ready() {
+            last_oneshot_state = oneshot_state;
+            sio.fifo
+                .write(ONESHOT_MODS | oneshot_state.0 as u32 | (oneshot_state.1 as u32) << 8);
+        }
+
+        // ? toggle the matrix diagnostics screen
+        // send message
+        if !diagnostics_toggled && key_resolver.diagnostics && sio.fifo.is_write_ready() {
+            diagnostics_toggled = true;
+            sio.fifo.write(DIAGNOSTICS_ON);
+        } else if !key_resolver.diagnostics && diagnostics_toggled && sio.fifo.is_write_ready() {
+            // reset
+            diagnostics_toggled = false;
+            sio.fifo.write(DIAGNOSTICS_OFF);
+        }
+        // send the next diagnostics value while the screen is shown
+        if diagnostics_toggled && diagnostics_count_down.wait().is_ok() && sio.fifo.is_write_ready()
+        {
+            let message = match diagnostics_message {
+                0 => DIAG_SCAN_RATE | scan_timing.frequency.min(MESSAGE_VALUE),
+                1 => DIAG_JITTER | scan_timing.jitter.min(MESSAGE_VALUE),
+                2 => DIAG_GHOSTS | ghosting.ghost_events.min(MESSAGE_VALUE),
+                3 => DIAG_BOUNCES | matrix_stats.total_bounces().min(MESSAGE_VALUE),
+                _ => match matrix_stats.worst() {
+                    Some((row, col)) => {
+                        let chatter = matrix_stats.key(row, col).chatter.min(0xFFFF);
+                        DIAG_CHATTER | (row as u32) << 24 | (col as u32) << 16 | chatter
+                    }
+                    None => DIAG_CHATTER,
+                },
+            };
+            sio.fifo.write(message);
+            diagnostics_message = (diagnostics_message + 1) % 5;
+        }
+
+        // ? toggle the matrix tester
+        // send message
+        if !matrix_tester_toggled && key_resolver.matrix_tester && sio.fifo.is_write_ready() {
+            matrix_tester_toggled = true;
+            tester_rows = [0; 5];
+            sio.fifo.write(MATRIX_TESTER_ON);
+        } else if !key_resolver.matrix_tester && matrix_tester_toggled && sio.fifo.is_write_ready()
+        {
+            // reset
+            matrix_tester_toggled = false;
+            sio.fifo.write(MATRIX_TESTER_OFF);
+        }
+        // send rows of pressed keys that have changed
+        if matrix_tester_toggled {
+            for (row, sent) in tester_rows.iter_mut().enumerate() {
+                let bits = pressed_keys.row(row) as u32;
+                if bits != *sent && sio.fifo.is_write_ready() {
+                    *sent = bits;
+                    sio.fifo.write(TESTER_ROW | (row as u32) << 16 | bits);
+                }
+            }
+        }
+
         // ? keyboard reporting
-        // write report every input_count_down
+        // write report every input_count_down - nothing is sent while the matrix tester is on
         if input_count_down.wait().is_ok() {
-            let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _>, _>();
-            // 2 separate functions for fn key and normal, more memory intensive but less cpu?
-            if pressed_keys[4][10] == 1 {
-                // fn key pressed
-                let keys = keys::get_fnkeys(pressed_keys);
-                match keyboard.write_report(&keys) {
-                    Err(UsbHidError::WouldBlock) => {}
-                    Err(UsbHidError::Duplicate) => {}
-                    Ok(_) => {}
-                    Err(e) => {
-                        core::panic!("Failed to write keyboard report: {:?}", e)
-                    }
-                };
+            if key_resolver.matrix_tester {
+                key_resolver.report_sent();
             } else {
-                // fn key released
-                let keys = keys::get_keys(pressed_keys);
-                match keyboard.write_report(&keys) {
+                let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _>, _>();
+                let report = key_resolver.report();
+                match keyboard.write_report(report.keys()) {
                     Err(UsbHidError::WouldBlock) => {}
-                    Err(UsbHidError::Duplicate) => {}
-                    Ok(_) => {}
+                    Err(UsbHidError::Duplicate) => key_resolver.report_sent(),
+                    Ok(_) => key_resolver.report_sent(),
                     Err(e) => {
                         core::panic!("Failed to write keyboard report: {:?}", e)
                     }
@@ -431,102 +819,133 @@ fn main() -> ! {
                     caps_on = led.caps_lock;
                 }
             }
+
+            // ? host requests for the matrix diagnostics
+            let mut request = [0; REPORT_SIZE];
+            match composite
+                .interface::<RawInterface<'_, _>, _>()
+                .read_report(&mut request)
+            {
+                Err(UsbError::WouldBlock) => {}
+                Err(e) => {
+                    core::panic!("Failed to read diagnostics report: {:?}", e)
+                }
+                Ok(n) => {
+                    if request[0] == MATRIX_TESTER {
+                        key_resolver.matrix_tester = request[1] != 0;
+                    }
+                    diagnostics_reply =
+                        matrix_stats.respond(&request[..n], &scan_timing, ghosting.ghost_events);
+                }
+            }
+        }
+        if let Some(reply) = diagnostics_reply {
+            match composite
+                .interface::<RawInterface<'_, _>, _>()
+                .write_report(&reply)
+            {
+                Err(UsbError::WouldBlock) => {}
+                Ok(_) => diagnostics_reply = None,
+                Err(e) => {
+                    core::panic!("Failed to write diagnostics report: {:?}", e)
+                }
+            }
         }
 
         // ? consumer reporting
         // write report every consumer_poll
+        // nothing is sent while the matrix tester is on
         if consumer_poll.wait().is_ok() {
-            let codes = consumer::get_consumer(
-                pressed_keys,
-                rot_rotation_dir,
-                rot_was_pressed,
-                rot_can_push,
-            );
+            // consumer actions from the keymap (e.g. rotary push tap dance and turns)
             let consumer_report = MultipleConsumerReport {
-                codes: [
-                    codes[0],
-                    Consumer::Unassigned, // can send more consumer codes with codes[1], codes[2], codes[3]
-                    Consumer::Unassigned,
-                    Consumer::Unassigned,
-                ],
+                codes: key_resolver.consumer_codes(),
             };
 
-            if last_consumer_report != consumer_report {
+            if !key_resolver.matrix_tester && last_consumer_report != consumer_report {
                 let consumer = composite.interface::<ConsumerControlInterface<'_, _>, _>();
                 match consumer.write_report(&consumer_report) {
                     Err(UsbError::WouldBlock) => {}
                     Ok(_) => {
                         last_consumer_report = consumer_report;
+                        key_resolver.consumer_report_sent();
                     }
                     Err(e) => {
                         core::panic!("Failed to write consumer report: {:?}", e)
                     }
                 };
+            } else {
+                key_resolver.consumer_report_sent();
             };
-            // reset rotary encoder states
-            rot_was_pressed = false;
-            rot_rotation_dir = 0;
         }
 
-        // ? poll the keys
-        // send signal for this col;
-        for i in 0..14 {
-            col_pins[i].into_push_pull_output();
-            col_pins[i].set_low().ok();
-            // read the value and set the pressed_keys value if read and confirmed_press
-            for j in 0..5 {
-                if row_pins[j].is_low().unwrap() {
-                    if debounce_keys[j][i] > confirmed_press {
-                        pressed_keys[j][i] = 1;
-                        // reset debounce
-                        debounce_keys[j][i] = 0;
-                    } else {
-                        // increment debounce
-                        debounce_keys[j][i] += 1;
-                    }
-                } else {
-                    if debounce_keys[j][i] < -confirmed_press {
-                        pressed_keys[j][i] = 0;
-                        // reset debounce
-                        debounce_keys[j][i] = 0;
-                    } else {
-                        // decrement debounce
-                        debounce_keys[j][i] -= 1;
+        // ? mouse reporting
+        // write the wheel every mouse_poll - only when scrolled, the wheel is relative
+        // nothing is sent while the matrix tester is on
+        if mouse_poll.wait().is_ok() {
+            let (vertical_wheel, horizontal_wheel) = key_resolver.wheel();
+            if !key_resolver.matrix_tester && (vertical_wheel, horizontal_wheel) != (0, 0) {
+                let mouse = composite.interface::<WheelMouseInterface<'_, _>, _>();
+                match mouse.write_report(&WheelMouseReport {
+                    vertical_wheel,
+                    horizontal_wheel,
+                    ..Default::default()
+                }) {
+                    Err(UsbHidError::WouldBlock) => {}
+                    Ok(_) => key_resolver.wheel_report_sent(),
+                    Err(e) => {
+                        core::panic!("Failed to write mouse report: {:?}", e)
                     }
-                }
+                };
+            } else {
+                key_resolver.wheel_report_sent();
             }
-            // then disable
-            col_pins[i].into_pull_up_input();
         }
 
-        // check if play/pause key has been pressed and set that it was pressed
-        if pressed_keys[1][13] == 1 && !rot_was_pressed {
-            rot_was_pressed = true;
+        // ? poll the keys every scan_count_down
+        let now = (timer.get_counter() / 1000) as u32;
+        if scan_count_down.wait().is_ok() {
+            #[cfg(feature = "software-scanner")]
+            scan_timing.record(timer.get_counter() as u32);
+            #[cfg(feature = "software-scanner")]
+            let mut scan = matrix.scan(&mut delay).unwrap();
+            #[cfg(not(feature = "software-scanner"))]
+            let mut scan = matrix.scan();
+            ghosting.filter(&mut scan);
+            let debounced = debouncer.update(&scan, now);
+            matrix_stats.update(&scan, debounced, ghosting.suspect(), now);
+            // set the pressed_keys value from the debounced scan and queue what changed
+            pressed_keys = KeyState::from_scan(debounced);
+            key_events.update(&pressed_keys, now);
         }
 
-        // ? poll the rotary encoder
-        // read values a and b and compare to last state and assign to rot_rotation_dir
-        if rot_a.is_low().unwrap() != rot_a_last_state {
-            if rot_a.is_low().unwrap() {
-                if rot_b.is_low().unwrap() {
-                    // clockwise
-                    rot_rotation_dir = 1;
-                    // disable push - play/pause will not activate if the encoder has also been rotated before its release
-                    // so we can have alternate pushed and rotated functionality without also activating play/pause after release.
-                    rot_can_push = false;
-                } else {
-                    // anticlockwise
-                    rot_rotation_dir = -1;
-                    // disable push - play/pause will not activate if the encoder has also been rotated before its release
-                    // so we can have alternate pushed and rotated functionality without also activating play/pause after release.
-                    rot_can_push = false;
-                }
+        // ? pass key presses and releases on to the combos and keymap
+        while let Some(event) = key_events.pop() {
+            for event in combos.event(event, &key_resolver.layers) {
+                key_resolver.combo_event(event);
+            }
+        }
+        for event in combos.tick(now, &key_resolver.layers) {
+            key_resolver.combo_event(event);
+        }
+        key_resolver.tick(now);
+        if key_resolver.bootloader {
+            hal::rom_data::reset_to_usb_boot(0, 0);
+        }
+
+        // ? poll the rotary encoders
+        // decode a and b and give a finished step to the keymap (1 clockwise, -1 anticlockwise)
+        for (index, encoder) in encoders.iter_mut().enumerate() {
+            let step = encoder.update().unwrap();
+            if step == 0 {
+                continue;
             }
-            // setup for next
-            rot_a_last_state = rot_a.is_low().unwrap();
-            // reset rot can push
-            if !rot_was_pressed && !rot_can_push {
-                rot_can_push = true;
+            let push = encoder.push();
+            let pushed = push.is_some_and(|(row, col)| pressed_keys.is_pressed(row, col));
+            key_resolver.encoder_turn(index, step, pushed);
+            // cancel the push tap dance - play/pause will not activate if the encoder has also been rotated before its release
+            // so we can have alternate pushed and rotated functionality without also activating play/pause after release.
+            if let Some((row, col)) = push {
+                key_resolver.cancel_key(row, col);
             }
         }
     }
@@ -0,0 +1,433 @@
+// Key matrix scanning over embedded-hal pins
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+// ? which way the diodes point
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiodeDirection {
+    // rows are driven low and columns are read
+    Col2Row,
+    // columns are driven low and rows are read (OrionsHands)
+    Row2Col,
+}
+
+// ? the matrix pins - each pin is read when it is an input and driven low when it is selected
+// selecting is set_low, unselecting is set_high (or going back to a pulled up input)
+pub struct Matrix<R, C, const ROWS: usize, const COLS: usize> {
+    rows: [R; ROWS],
+    cols: [C; COLS],
+    diode_direction: DiodeDirection,
+    // time (us) to wait after selecting each line before reading, the last one is used for any lines after it
+    settle_us: &'static [u32],
+}
+
+impl<R, C, E, const ROWS: usize, const COLS: usize> Matrix<R, C, ROWS, COLS>
+where
+    R: InputPin<Error = E> + OutputPin<Error = E>,
+    C: InputPin<Error = E> + OutputPin<Error = E>,
+{
+    pub fn new(
+        rows: [R; ROWS],
+        cols: [C; COLS],
+        diode_direction: DiodeDirection,
+    ) -> Result<Self, E> {
+        let mut matrix = Matrix {
+            rows,
+            cols,
+            diode_direction,
+            settle_us: &[],
+        };
+        // nothing selected to begin with
+        for row in matrix.rows.iter_mut() {
+            row.set_high()?;
+        }
+        for col in matrix.cols.iter_mut() {
+            col.set_high()?;
+        }
+        Ok(matrix)
+    }
+
+    // ? settle delays (us) for each selected line (columns for Row2Col, rows for Col2Row)
+    // a single value is used for every line, none for no delay
+    pub fn set_settle_us(&mut self, settle_us: &'static [u32]) {
+        self.settle_us = settle_us;
+    }
+
+    // ? idle - every column (or row for Col2Row) is selected so any key press pulls its line low, for waking up on
+    pub fn set_idle(&mut self, idle: bool) -> Result<(), E> {
+        match self.diode_direction {
+            DiodeDirection::Row2Col => {
+                for col in self.cols.iter_mut() {
+                    if idle {
+                        col.set_low()?;
+                    } else {
+                        col.set_high()?;
+                    }
+                }
+            }
+            DiodeDirection::Col2Row => {
+                for row in self.rows.iter_mut() {
+                    if idle {
+                        row.set_low()?;
+                    } else {
+                        row.set_high()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // ? scan every key - bit n of each row is set when the key in column n is pressed
+    pub fn scan<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<[u32; ROWS], E> {
+        let mut state = [0; ROWS];
+        match self.diode_direction {
+            DiodeDirection::Row2Col => {
+                for (col, col_pin) in self.cols.iter_mut().enumerate() {
+                    col_pin.set_low()?;
+                    settle(self.settle_us, col, delay);
+                    for (row, row_pin) in self.rows.iter().enumerate() {
+                        if row_pin.is_low()? {
+                            state[row] |= 1 << col;
+                        }
+                    }
+                    col_pin.set_high()?;
+                }
+            }
+            DiodeDirection::Col2Row => {
+                for (row, row_pin) in self.rows.iter_mut().enumerate() {
+                    row_pin.set_low()?;
+                    settle(self.settle_us, row, delay);
+                    for (col, col_pin) in self.cols.iter().enumerate() {
+                        if col_pin.is_low()? {
+                            state[row] |= 1 << col;
+                        }
+                    }
+                    row_pin.set_high()?;
+                }
+            }
+        }
+        Ok(state)
+    }
+}
+
+// wait for a selected line to settle before it is read
+fn settle<D: DelayUs<u32>>(settle_us: &[u32], line: usize, delay: &mut D) {
+    let us = settle_us
+        .get(line)
+        .or(settle_us.last())
+        .copied()
+        .unwrap_or(0);
+    if us > 0 {
+        delay.delay_us(us);
+    }
+}
+
+// ? measured scan rate - scans counted over each second, and the spread of the time between scans
+pub struct ScanTiming {
+    // time (us) of the last scan and of the start of this second
+    last: Option<u32>,
+    window_start: u32,
+    // this second so far
+    count: u32,
+    min_interval: u32,
+    max_interval: u32,
+    // scans in the last full second (Hz)
+    pub frequency: u32,
+    // longest minus shortest time between scans in the last full second (us)
+    pub jitter: u32,
+}
+
+impl ScanTiming {
+    pub const fn new() -> Self {
+        ScanTiming {
+            last: None,
+            window_start: 0,
+            count: 0,
+            min_interval: u32::MAX,
+            max_interval: 0,
+            frequency: 0,
+            jitter: 0,
+        }
+    }
+
+    // ? a scanner running at a fixed rate without the cpu (e.g. the PIO scanner) - nothing to measure, no jitter
+    pub const fn fixed(frequency: u32) -> Self {
+        ScanTiming {
+            frequency,
+            ..Self::new()
+        }
+    }
+
+    // ? note a scan, call every scan with the current time in us
+    pub fn record(&mut self, now: u32) {
+        let last = match self.last.replace(now) {
+            Some(last) => last,
+            None => {
+                self.window_start = now;
+                return;
+            }
+        };
+        let interval = now.wrapping_sub(last);
+        self.count += 1;
+        self.min_interval = self.min_interval.min(interval);
+        self.max_interval = self.max_interval.max(interval);
+        if now.wrapping_sub(self.window_start) >= 1_000_000 {
+            self.frequency = self.count;
+            self.jitter = self.max_interval - self.min_interval;
+            self.window_start = now;
+            self.count = 0;
+            self.min_interval = u32::MAX;
+            self.max_interval = 0;
+        }
+    }
+}
+
+impl Default for ScanTiming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ? what to do with a key that may be a ghost
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GhostPolicy {
+    // leave it out until the rectangle is broken
+    Block,
+    // let it through but mark it as suspect - marked keys are counted per key in the matrix diagnostics
+    Mark,
+}
+
+// ? ghosting detection - a key that completes a rectangle with three other pressed keys may not really be pressed
+// (r1, c1) (r1, c2) (r2, c1) pressed can make (r2, c2) look pressed if a diode fails or is shorted
+pub struct Ghosting<const ROWS: usize> {
+    policy: GhostPolicy,
+    // keys let through at the last scan
+    last: [u32; ROWS],
+    // keys that may be ghosts, bit per column
+    suspect: [u32; ROWS],
+    // number of times a new ghost was found
+    pub ghost_events: u32,
+}
+
+impl<const ROWS: usize> Ghosting<ROWS> {
+    pub const fn new(policy: GhostPolicy) -> Self {
+        Ghosting {
+            policy,
+            last: [0; ROWS],
+            suspect: [0; ROWS],
+            ghost_events: 0,
+        }
+    }
+
+    // ? check a scan for ghosts - blocked keys are cleared from the scan
+    pub fn filter(&mut self, state: &mut [u32; ROWS]) {
+        let raw = *state;
+        let mut suspect = [0; ROWS];
+        for row in 0..ROWS {
+            // only newly pressed keys can be ghosts, keys already down were pressed first
+            let new = raw[row] & !self.last[row];
+            for col in 0..32 {
+                let bit = 1 << col;
+                if new & bit != 0 && in_rectangle(&raw, row, bit) {
+                    suspect[row] |= bit;
+                }
+            }
+            // marked keys stay suspect until they are released
+            suspect[row] |= self.suspect[row] & raw[row];
+            if suspect[row] & !self.suspect[row] != 0 {
+                self.ghost_events += 1;
+            }
+        }
+        self.suspect = suspect;
+        if self.policy == GhostPolicy::Block {
+            for (row, suspect) in state.iter_mut().zip(suspect.iter()) {
+                *row &= !suspect;
+            }
+        }
+        self.last = *state;
+    }
+
+    // ? keys that may be ghosts, bit per column
+    pub fn suspect(&self) -> &[u32; ROWS] {
+        &self.suspect
+    }
+}
+
+// another row has this column pressed and shares another pressed column with this row
+fn in_rectangle<const ROWS: usize>(state: &[u32; ROWS], row: usize, bit: u32) -> bool {
+    state.iter().enumerate().any(|(other, other_state)| {
+        other != row && other_state & bit != 0 && state[row] & other_state & !bit != 0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // ? a board with pressed keys - a driven line pulls low the lines it is connected to through pressed keys
+    struct Board {
+        diode_direction: DiodeDirection,
+        pressed: Vec<(usize, usize)>,
+        rows_low: [bool; 3],
+        cols_low: [bool; 4],
+    }
+
+    impl Board {
+        fn new(diode_direction: DiodeDirection, pressed: &[(usize, usize)]) -> Rc<RefCell<Board>> {
+            Rc::new(RefCell::new(Board {
+                diode_direction,
+                pressed: pressed.to_vec(),
+                rows_low: [false; 3],
+                cols_low: [false; 4],
+            }))
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Line {
+        Row(usize),
+        Col(usize),
+    }
+
+    struct MockPin {
+        board: Rc<RefCell<Board>>,
+        line: Line,
+    }
+
+    impl MockPin {
+        fn set(&self, low: bool) {
+            let mut board = self.board.borrow_mut();
+            match self.line {
+                Line::Row(row) => board.rows_low[row] = low,
+                Line::Col(col) => board.cols_low[col] = low,
+            }
+        }
+    }
+
+    impl OutputPin for MockPin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.set(true);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.set(false);
+            Ok(())
+        }
+    }
+
+    impl InputPin for MockPin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(!self.is_low()?)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            let board = self.board.borrow();
+            // current only flows one way through the diodes
+            Ok(match (self.line, board.diode_direction) {
+                (Line::Row(row), DiodeDirection::Row2Col) => {
+                    board.rows_low[row]
+                        || board
+                            .pressed
+                            .iter()
+                            .any(|(r, c)| *r == row && board.cols_low[*c])
+                }
+                (Line::Col(col), DiodeDirection::Col2Row) => {
+                    board.cols_low[col]
+                        || board
+                            .pressed
+                            .iter()
+                            .any(|(r, c)| *c == col && board.rows_low[*r])
+                }
+                (Line::Row(row), _) => board.rows_low[row],
+                (Line::Col(col), _) => board.cols_low[col],
+            })
+        }
+    }
+
+    // total time waited
+    struct MockDelay(u32);
+
+    impl DelayUs<u32> for MockDelay {
+        fn delay_us(&mut self, us: u32) {
+            self.0 += us;
+        }
+    }
+
+    fn new_matrix(board: &Rc<RefCell<Board>>) -> Matrix<MockPin, MockPin, 3, 4> {
+        let pin = |line| MockPin {
+            board: board.clone(),
+            line,
+        };
+        let diode_direction = board.borrow().diode_direction;
+        Matrix::new(
+            [0, 1, 2].map(|row| pin(Line::Row(row))),
+            [0, 1, 2, 3].map(|col| pin(Line::Col(col))),
+            diode_direction,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn scan_row2col() {
+        let board = Board::new(DiodeDirection::Row2Col, &[(0, 0), (1, 3), (2, 1), (2, 2)]);
+        let mut matrix = new_matrix(&board);
+        let scan = matrix.scan(&mut MockDelay(0)).unwrap();
+        assert_eq!(scan, [0b0001, 0b1000, 0b0110]);
+        // nothing is left selected
+        assert_eq!(board.borrow().cols_low, [false; 4]);
+    }
+
+    #[test]
+    fn scan_col2row() {
+        let board = Board::new(DiodeDirection::Col2Row, &[(0, 2), (1, 0), (1, 1)]);
+        let mut matrix = new_matrix(&board);
+        let scan = matrix.scan(&mut MockDelay(0)).unwrap();
+        assert_eq!(scan, [0b0100, 0b0011, 0b0000]);
+        assert_eq!(board.borrow().rows_low, [false; 3]);
+    }
+
+    #[test]
+    fn settle_delay_for_each_line() {
+        let board = Board::new(DiodeDirection::Row2Col, &[]);
+        let mut matrix = new_matrix(&board);
+        let mut delay = MockDelay(0);
+        matrix.scan(&mut delay).unwrap();
+        assert_eq!(delay.0, 0);
+        // the last value is used for the rest of the lines
+        matrix.set_settle_us(&[10, 5]);
+        matrix.scan(&mut delay).unwrap();
+        assert_eq!(delay.0, 10 + 5 + 5 + 5);
+    }
+
+    #[test]
+    fn idle_selects_every_line() {
+        let board = Board::new(DiodeDirection::Row2Col, &[(1, 2)]);
+        let mut matrix = new_matrix(&board);
+        matrix.set_idle(true).unwrap();
+        assert_eq!(board.borrow().cols_low, [true; 4]);
+        // a press pulls its row low for the wake up
+        assert!(!matrix.rows[0].is_low().unwrap());
+        assert!(matrix.rows[1].is_low().unwrap());
+        matrix.set_idle(false).unwrap();
+        assert_eq!(board.borrow().cols_low, [false; 4]);
+
+        let board = Board::new(DiodeDirection::Col2Row, &[(1, 2)]);
+        let mut matrix = new_matrix(&board);
+        matrix.set_idle(true).unwrap();
+        assert_eq!(board.borrow().rows_low, [true; 3]);
+        assert!(matrix.cols[2].is_low().unwrap());
+        assert!(!matrix.cols[0].is_low().unwrap());
+        matrix.set_idle(false).unwrap();
+        assert_eq!(board.borrow().rows_low, [false; 3]);
+    }
+}
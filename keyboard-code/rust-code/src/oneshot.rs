@@ -0,0 +1,116 @@
+// One-shot (sticky) modifiers - a modifier tap applies to the next key press only
+
+use usbd_human_interface_device::page::Keyboard;
+
+// armed modifiers are cleared if no key is pressed within this time (ms)
+pub const ONESHOT_TIMEOUT: u32 = 3000;
+// tapping the same one-shot modifier twice within this time (ms) locks it on
+pub const ONESHOT_LOCK_TERM: u32 = 300;
+
+// ? modifier keys as bits - LeftControl is bit 0 up to RightGUI as bit 7, same as the hid report
+pub fn modifier_bit(key: Keyboard) -> Option<u8> {
+    let code = key as u8;
+    if (Keyboard::LeftControl as u8..=Keyboard::RightGUI as u8).contains(&code) {
+        Some(1 << (code - Keyboard::LeftControl as u8))
+    } else {
+        None
+    }
+}
+
+pub struct OneShotMods {
+    // waiting for the next key
+    armed: u8,
+    // on until tapped again
+    locked: u8,
+    // one-shot keys held down, they act as normal modifiers if another key is pressed meanwhile
+    held: u8,
+    // used by a key press, let go once that report is sent
+    used: u8,
+    // time the modifiers were armed
+    armed_at: u32,
+    // last one-shot modifier tapped and when, for locking
+    last_tap: Option<(u8, u32)>,
+}
+
+impl OneShotMods {
+    pub const fn new() -> Self {
+        OneShotMods {
+            armed: 0,
+            locked: 0,
+            held: 0,
+            used: 0,
+            armed_at: 0,
+            last_tap: None,
+        }
+    }
+
+    // ? one-shot modifier key pressed
+    pub fn press(&mut self, key: Keyboard, now: u32) {
+        let bit = match modifier_bit(key) {
+            Some(bit) => bit,
+            None => return,
+        };
+        self.held |= bit;
+        if self.locked & bit != 0 {
+            // tapped again - unlock
+            self.locked &= !bit;
+            self.last_tap = None;
+        } else if matches!(self.last_tap, Some((last, time)) if last == bit && now.wrapping_sub(time) < ONESHOT_LOCK_TERM)
+        {
+            // double tap - lock
+            self.armed &= !bit;
+            self.locked |= bit;
+            self.last_tap = None;
+        } else {
+            self.armed |= bit;
+            self.armed_at = now;
+            self.last_tap = Some((bit, now));
+        }
+    }
+
+    pub fn release(&mut self, key: Keyboard) {
+        if let Some(bit) = modifier_bit(key) {
+            self.held &= !bit;
+        }
+    }
+
+    // ? a normal key was pressed - armed modifiers apply to it and are then let go
+    pub fn key_pressed(&mut self, key: Keyboard) {
+        if modifier_bit(key).is_none() {
+            self.used |= self.armed;
+            self.last_tap = None;
+        }
+    }
+
+    // ? call once a report has been sent
+    pub fn report_sent(&mut self) {
+        self.armed &= !self.used;
+        self.used = 0;
+    }
+
+    // ? clear armed modifiers that have timed out, call every loop with the current time in ms
+    pub fn tick(&mut self, now: u32) {
+        if self.armed & !self.held != 0 && now.wrapping_sub(self.armed_at) >= ONESHOT_TIMEOUT {
+            self.armed &= self.held;
+        }
+    }
+
+    // ? modifiers to add to the report
+    pub fn keys(&self) -> impl Iterator<Item = Keyboard> {
+        let mods = self.armed | self.locked | self.held;
+        (0..8)
+            .filter(move |bit| mods & (1 << bit) != 0)
+            .map(|bit| Keyboard::from(Keyboard::LeftControl as u8 + bit))
+    }
+
+    // ? armed and locked modifiers as bits, for the display
+    pub fn state(&self) -> (u8, u8) {
+        (self.armed, self.locked)
+    }
+}
+
+impl Default for OneShotMods {
+    fn default() -> Self {
+        Self::new()
+    }
+}
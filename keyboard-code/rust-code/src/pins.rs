@@ -0,0 +1,68 @@
+// rp2040 pin wrappers
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use rp2040_hal::gpio::{DynPin, Error};
+
+// ? matrix pin - a pulled up input until it is selected, then a push pull output driven low
+// switching back to an input rather than driving high means unselected lines never fight each other
+pub struct MatrixPin(DynPin);
+
+impl MatrixPin {
+    pub fn new(mut pin: DynPin) -> Self {
+        pin.into_pull_up_input();
+        MatrixPin(pin)
+    }
+}
+
+impl OutputPin for MatrixPin {
+    type Error = Error;
+
+    fn set_low(&mut self) -> Result<(), Error> {
+        self.0.into_push_pull_output();
+        self.0.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Error> {
+        self.0.into_pull_up_input();
+        Ok(())
+    }
+}
+
+// ? encoder pin - a pulled up input
+pub struct EncoderPin(DynPin);
+
+impl EncoderPin {
+    pub fn new(mut pin: DynPin) -> Self {
+        pin.into_pull_up_input();
+        EncoderPin(pin)
+    }
+
+    // gpio number, for the idle wake up
+    pub fn gpio(&self) -> u8 {
+        self.0.id().num
+    }
+}
+
+impl InputPin for EncoderPin {
+    type Error = Error;
+
+    fn is_high(&self) -> Result<bool, Error> {
+        self.0.is_high()
+    }
+
+    fn is_low(&self) -> Result<bool, Error> {
+        self.0.is_low()
+    }
+}
+
+impl InputPin for MatrixPin {
+    type Error = Error;
+
+    fn is_high(&self) -> Result<bool, Error> {
+        self.0.is_high()
+    }
+
+    fn is_low(&self) -> Result<bool, Error> {
+        self.0.is_low()
+    }
+}
@@ -0,0 +1,220 @@
+// Key matrix scanning on a PIO state machine - the columns are driven and the rows sampled without the cpu,
+// DMA copies the rows read for each column into a buffer that always holds the latest scan
+// the settle delay is part of the program, so it is shared by every column - it can't be set per column like the software scanner
+
+use rp2040_hal::pac;
+use rp2040_hal::pio::{
+    PIOBuilder, PinDir, PinState, Running, Rx, ShiftDirection, StateMachine, UninitStateMachine,
+    PIO, PIO0SM0,
+};
+
+// lines driven each scan - the DMA ring buffer has to be a power of two, lines past the columns drive nothing
+const LINES: usize = 16;
+// DMA ring size as a power of two in bytes (16 words)
+const RING_BITS: u8 = 6;
+// words copied before the DMA channel has to be started again, a whole number of scans
+const TRANSFERS: u32 = 0xFFFF_FFF0;
+// instructions per line apart from the settle delay, and per scan apart from the lines
+const LINE_CYCLES: u32 = 10;
+const SCAN_CYCLES: u32 = 2;
+// DMA channel used for the scan
+const DMA_CHANNEL: usize = 0;
+
+// rows read for each line, written by DMA - all high is nothing pressed
+#[repr(C, align(64))]
+struct ScanBuffer([u32; LINES]);
+static mut SCAN_BUFFER: ScanBuffer = ScanBuffer([u32::MAX; LINES]);
+
+// ? the matrix pins are given to PIO0 - rows have to be next to each other, and so do columns
+// columns are driven low by making them outputs (the output value is always low), rows are read
+pub struct PioMatrix<const ROWS: usize, const COLS: usize> {
+    // gpio numbers in matrix order
+    rows: [u8; ROWS],
+    cols: [u8; COLS],
+    row_base: u8,
+    col_base: u8,
+    // scans per second the state machine runs at
+    scan_rate: u32,
+    dma: pac::DMA,
+    _sm: StateMachine<PIO0SM0, Running>,
+    _rx: Rx<PIO0SM0>,
+}
+
+impl<const ROWS: usize, const COLS: usize> PioMatrix<ROWS, COLS> {
+    // settle_cycles - state machine cycles (up to 31) between driving a column and reading the rows
+    // scan_rate - target scans per second, the state machine clock is divided down to match
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pio: &mut PIO<pac::PIO0>,
+        sm: UninitStateMachine<PIO0SM0>,
+        dma: pac::DMA,
+        resets: &mut pac::RESETS,
+        rows: [u8; ROWS],
+        cols: [u8; COLS],
+        settle_cycles: u8,
+        scan_rate: u32,
+        sys_clock_hz: u32,
+    ) -> Self {
+        let row_base = *rows.iter().min().unwrap();
+        let col_base = *cols.iter().min().unwrap();
+        if rows.iter().any(|row| row - row_base >= ROWS as u8)
+            || cols.iter().any(|col| col - col_base >= COLS as u8)
+            || COLS > LINES
+        {
+            core::panic!("PIO matrix pins have to be next to each other");
+        }
+
+        // ? program - drive each line in turn, wait, push the rows read
+        let mut a = pio::Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut line = a.label();
+        a.bind(&mut wrap_target);
+        a.set(pio::SetDestination::X, LINES as u8 - 1);
+        // walking bit for the line being driven
+        a.set(pio::SetDestination::Y, 1);
+        a.bind(&mut line);
+        a.mov(
+            pio::MovDestination::OSR,
+            pio::MovOperation::None,
+            pio::MovSource::Y,
+        );
+        a.out(pio::OutDestination::PINDIRS, COLS as u8);
+        a.nop_with_delay(settle_cycles.min(31));
+        a.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::None,
+            pio::MovSource::NULL,
+        );
+        a.r#in(pio::InSource::PINS, ROWS as u8);
+        a.push(false, true);
+        // move the walking bit on to the next line
+        a.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::None,
+            pio::MovSource::Y,
+        );
+        a.r#in(pio::InSource::NULL, 1);
+        a.mov(
+            pio::MovDestination::Y,
+            pio::MovOperation::None,
+            pio::MovSource::ISR,
+        );
+        a.jmp(pio::JmpCondition::XDecNonZero, &mut line);
+        a.bind(&mut wrap_source);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+        let installed = pio.install(&program).unwrap();
+
+        // clock divided so a scan takes 1 / scan_rate
+        let cycles = SCAN_CYCLES + LINES as u32 * (LINE_CYCLES + settle_cycles.min(31) as u32);
+        let divisor = (sys_clock_hz as f32 / (scan_rate * cycles) as f32).clamp(1.0, 65535.0);
+        let (mut sm, rx, _) = PIOBuilder::from_program(installed)
+            .out_pins(col_base, COLS as u8)
+            .in_pin_base(row_base)
+            .in_shift_direction(ShiftDirection::Left)
+            .out_shift_direction(ShiftDirection::Right)
+            .clock_divisor(divisor)
+            .build(sm);
+        sm.set_pins(cols.iter().map(|col| (*col, PinState::Low)));
+        sm.set_pindirs(
+            cols.iter()
+                .chain(rows.iter())
+                .map(|pin| (*pin, PinDir::Input)),
+        );
+
+        // rows pulled up, columns left floating when not driven - the pio function clears the pulls
+        let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+        for row in rows.iter() {
+            pads.gpio[*row as usize].modify(|_, w| w.pue().set_bit().pde().clear_bit());
+        }
+        for col in cols.iter() {
+            pads.gpio[*col as usize].modify(|_, w| w.pue().clear_bit().pde().clear_bit());
+        }
+
+        // ? DMA from the rx fifo into the ring buffer
+        resets.reset.modify(|_, w| w.dma().clear_bit());
+        while resets.reset_done.read().dma().bit_is_clear() {}
+        let ch = &dma.ch[DMA_CHANNEL];
+        ch.ch_read_addr
+            .write(|w| unsafe { w.bits(rx.fifo_address() as u32) });
+        ch.ch_write_addr
+            .write(|w| unsafe { w.bits(core::ptr::addr_of!(SCAN_BUFFER) as u32) });
+        ch.ch_trans_count.write(|w| unsafe { w.bits(TRANSFERS) });
+        ch.ch_ctrl_trig.write(|w| unsafe {
+            w.data_size().size_word();
+            w.incr_read().clear_bit();
+            w.incr_write().set_bit();
+            // wrap the write address
+            w.ring_sel().set_bit();
+            w.ring_size().bits(RING_BITS);
+            w.treq_sel().bits(rx.dreq_value());
+            // chained to itself - no chaining
+            w.chain_to().bits(DMA_CHANNEL as u8);
+            w.en().set_bit()
+        });
+
+        PioMatrix {
+            rows,
+            cols,
+            row_base,
+            col_base,
+            scan_rate: (sys_clock_hz as f32 / (divisor * cycles as f32)) as u32,
+            dma,
+            _sm: sm.start(),
+            _rx: rx,
+        }
+    }
+
+    // ? scans per second the state machine is running at
+    pub fn scan_rate(&self) -> u32 {
+        self.scan_rate
+    }
+
+    // ? idle - the state machine is paused and every column is driven low so any key press pulls its row low
+    pub fn set_idle(&mut self, idle: bool) {
+        let pio = unsafe { &*pac::PIO0::ptr() };
+        let io = unsafe { &*pac::IO_BANK0::ptr() };
+        // state machine 0 is the scanner
+        pio.ctrl.modify(|r, w| unsafe {
+            let others = r.sm_enable().bits() & !1;
+            w.sm_enable().bits(if idle { others } else { others | 1 })
+        });
+        for col in self.cols.iter() {
+            // the pio output value is always low, so forcing the output on drives the column low
+            io.gpio[*col as usize].gpio_ctrl.modify(|_, w| {
+                if idle {
+                    w.oeover().enable()
+                } else {
+                    w.oeover().normal()
+                }
+            });
+        }
+    }
+
+    // ? latest scan - bit n of each row is set when the key in column n is pressed
+    pub fn scan(&mut self) -> [u32; ROWS] {
+        // start the DMA again once it has run through its transfers
+        let ch = &self.dma.ch[DMA_CHANNEL];
+        if ch.ch_ctrl_trig.read().busy().bit_is_clear() {
+            ch.ch_write_addr
+                .write(|w| unsafe { w.bits(core::ptr::addr_of!(SCAN_BUFFER) as u32) });
+            ch.ch_al1_trans_count_trig
+                .write(|w| unsafe { w.bits(TRANSFERS) });
+        }
+
+        let mut state = [0; ROWS];
+        for (col, col_pin) in self.cols.iter().enumerate() {
+            let line = (col_pin - self.col_base) as usize;
+            let rows_read = unsafe {
+                core::ptr::read_volatile((core::ptr::addr_of!(SCAN_BUFFER) as *const u32).add(line))
+            };
+            for (row, row_pin) in self.rows.iter().enumerate() {
+                // pulled up - low is pressed
+                if rows_read & (1 << (row_pin - self.row_base)) == 0 {
+                    state[row] |= 1 << col;
+                }
+            }
+        }
+        state
+    }
+}
@@ -0,0 +1,157 @@
+// Rotary encoder quadrature decoding - both channels are followed through the gray code so bounces cancel out
+
+// ? step change for each (last state, new state), a state is (a << 1) | b with 1 for a pin pulled low
+// clockwise runs 00 -> 01 -> 11 -> 10 -> 00, both pins changing at once is a missed state and is ignored
+#[rustfmt::skip]
+const TRANSITIONS: [i8; 16] = [
+    // new: 00  01  10  11
+             0,  1, -1,  0, // last 00
+            -1,  0,  0,  1, // last 01
+             1,  0,  0, -1, // last 10
+             0, -1,  1,  0, // last 11
+];
+
+// ? steps reported for each full gray code cycle - has to match the detents of the encoder
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Resolution {
+    // one step per cycle, the detent is with both pins high
+    Full,
+    // two steps per cycle, detents with both pins high and both low
+    Half,
+    // a step for every state change
+    Quarter,
+}
+
+impl Resolution {
+    // state changes per step
+    const fn transitions(self) -> i8 {
+        match self {
+            Resolution::Full => 4,
+            Resolution::Half => 2,
+            Resolution::Quarter => 1,
+        }
+    }
+
+    fn is_detent(self, state: u8) -> bool {
+        match self {
+            Resolution::Full => state == 0b00,
+            Resolution::Half => state == 0b00 || state == 0b11,
+            Resolution::Quarter => true,
+        }
+    }
+}
+
+pub struct Quadrature {
+    resolution: Resolution,
+    // pin state at the last update
+    state: u8,
+    // state changes since the last detent, positive is clockwise
+    count: i8,
+}
+
+impl Quadrature {
+    // starts at rest with both pins high
+    pub const fn new(resolution: Resolution) -> Self {
+        Quadrature {
+            resolution,
+            state: 0b00,
+            count: 0,
+        }
+    }
+
+    // ? call with the pins (true when low) as often as possible, returns 1 for a clockwise step, -1 anticlockwise, 0 none
+    // a step is only counted once the encoder reaches a detent having come more than half way from the last one,
+    // so bouncing between two states or turning back before the detent counts nothing
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let state = (a as u8) << 1 | b as u8;
+        if state == self.state {
+            return 0;
+        }
+        self.count += TRANSITIONS[(self.state << 2 | state) as usize];
+        self.state = state;
+        if !self.resolution.is_detent(state) {
+            return 0;
+        }
+        let threshold = (self.resolution.transitions() / 2).max(1);
+        let step = if self.count >= threshold {
+            1
+        } else if self.count <= -threshold {
+            -1
+        } else {
+            0
+        };
+        self.count = 0;
+        step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one clockwise cycle and one anticlockwise from rest
+    const CLOCKWISE: &[u8] = &[0b01, 0b11, 0b10, 0b00];
+    const ANTICLOCKWISE: &[u8] = &[0b10, 0b11, 0b01, 0b00];
+
+    // ? feed pin states (a << 1) | b from rest and return the steps reported
+    fn replay(resolution: Resolution, states: &[u8]) -> std::vec::Vec<i8> {
+        let mut quadrature = Quadrature::new(resolution);
+        states
+            .iter()
+            .map(|state| quadrature.update(state & 0b10 != 0, state & 0b01 != 0))
+            .filter(|step| *step != 0)
+            .collect()
+    }
+
+    #[test]
+    fn full_steps() {
+        assert_eq!(replay(Resolution::Full, CLOCKWISE), [1]);
+        assert_eq!(replay(Resolution::Full, ANTICLOCKWISE), [-1]);
+        assert_eq!(
+            replay(Resolution::Full, &[CLOCKWISE, CLOCKWISE].concat()),
+            [1, 1]
+        );
+        // a bounce on each edge
+        assert_eq!(
+            replay(
+                Resolution::Full,
+                &[0b01, 0b00, 0b01, 0b11, 0b01, 0b11, 0b10, 0b11, 0b10, 0b00]
+            ),
+            [1]
+        );
+        // turned back before the detent
+        assert_eq!(replay(Resolution::Full, &[0b01, 0b11, 0b01, 0b00]), []);
+        // a missed state counts nothing, the other two changes still make the step
+        assert_eq!(replay(Resolution::Full, &[0b01, 0b11, 0b00]), [1]);
+        assert_eq!(replay(Resolution::Full, &[0b11, 0b10, 0b00]), [1]);
+    }
+
+    #[test]
+    fn half_steps() {
+        assert_eq!(replay(Resolution::Half, CLOCKWISE), [1, 1]);
+        assert_eq!(replay(Resolution::Half, ANTICLOCKWISE), [-1, -1]);
+        // a bounce on each edge
+        assert_eq!(
+            replay(Resolution::Half, &[0b01, 0b00, 0b01, 0b11, 0b01, 0b11]),
+            [1]
+        );
+        // turned back before the detent
+        assert_eq!(replay(Resolution::Half, &[0b01, 0b00]), []);
+        assert_eq!(replay(Resolution::Half, &[0b01, 0b11, 0b10, 0b11]), [1]);
+        // a missed state counts nothing and the next step is still made
+        assert_eq!(replay(Resolution::Half, &[0b11, 0b10, 0b00]), [1]);
+    }
+
+    #[test]
+    fn quarter_steps() {
+        assert_eq!(replay(Resolution::Quarter, CLOCKWISE), [1, 1, 1, 1]);
+        assert_eq!(replay(Resolution::Quarter, ANTICLOCKWISE), [-1, -1, -1, -1]);
+        // every state is a detent so a bounce or turning back undoes its own step
+        assert_eq!(
+            replay(Resolution::Quarter, &[0b01, 0b00, 0b01, 0b11]),
+            [1, -1, 1, 1]
+        );
+        // a missed state counts nothing and the next step is still made
+        assert_eq!(replay(Resolution::Quarter, &[0b11, 0b10]), [1]);
+    }
+}
@@ -0,0 +1,113 @@
+// Tap dance - tap once, tap twice or hold a key for different actions
+
+use crate::keys::Action;
+use heapless::Vec;
+
+// most tap dances in the tap dance list
+pub const MAX_TAP_DANCES: usize = 8;
+
+pub struct TapDanceDef {
+    pub tap: Action,
+    pub double_tap: Action,
+    pub hold: Action,
+    // time (ms) to wait for another tap, or to hold the key down for the hold action
+    pub term: u32,
+}
+
+#[derive(Clone, Copy)]
+struct DanceState {
+    // taps counted so far
+    taps: u8,
+    pressed: bool,
+    // time of the last press or release
+    timestamp: u32,
+    // action sent by this dance, released with the key
+    active: Action,
+}
+
+pub struct TapDances {
+    defs: &'static [TapDanceDef],
+    states: [DanceState; MAX_TAP_DANCES],
+}
+
+impl TapDances {
+    pub const fn new(defs: &'static [TapDanceDef]) -> Self {
+        TapDances {
+            defs,
+            states: [DanceState {
+                taps: 0,
+                pressed: false,
+                timestamp: 0,
+                active: Action::No,
+            }; MAX_TAP_DANCES],
+        }
+    }
+
+    // ? tap dance key pressed - the second tap sends the double tap action straight away
+    pub fn press(&mut self, index: usize, timestamp: u32) -> Option<(Action, bool)> {
+        let state = &mut self.states[index];
+        state.taps += 1;
+        state.pressed = true;
+        state.timestamp = timestamp;
+        if state.taps >= 2 {
+            state.taps = 0;
+            state.active = self.defs[index].double_tap;
+            Some((state.active, true))
+        } else {
+            None
+        }
+    }
+
+    // ? tap dance key released - releases the action it sent, if any
+    pub fn release(&mut self, index: usize, timestamp: u32) -> Option<(Action, bool)> {
+        let state = &mut self.states[index];
+        state.pressed = false;
+        state.timestamp = timestamp;
+        if state.active != Action::No {
+            let action = state.active;
+            state.active = Action::No;
+            Some((action, false))
+        } else {
+            None
+        }
+    }
+
+    // ? stop a dance without sending anything, releases its action if one was already sent
+    pub fn cancel(&mut self, index: usize) -> Option<(Action, bool)> {
+        let state = &mut self.states[index];
+        state.taps = 0;
+        if state.active != Action::No {
+            let action = state.active;
+            state.active = Action::No;
+            Some((action, false))
+        } else {
+            None
+        }
+    }
+
+    // ? check timers - returns actions to press (true) or release (false) in order
+    pub fn tick(&mut self, now: u32) -> Vec<(Action, bool), { 2 * MAX_TAP_DANCES }> {
+        let mut out = Vec::new();
+        for (state, def) in self.states.iter_mut().zip(self.defs.iter()) {
+            if state.taps == 0 || now.wrapping_sub(state.timestamp) < def.term {
+                continue;
+            }
+            state.taps = 0;
+            if state.pressed {
+                // held down past the term
+                state.active = def.hold;
+                out.push((def.hold, true)).ok();
+            } else {
+                // single tap and no second one came
+                out.push((def.tap, true)).ok();
+                out.push((def.tap, false)).ok();
+            }
+        }
+        out
+    }
+
+    // ? actions currently held down by tap dances
+    pub fn held(&self) -> impl Iterator<Item = &Action> {
+        self.states.iter().map(|state| &state.active)
+    }
+}
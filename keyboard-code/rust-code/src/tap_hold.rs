@@ -0,0 +1,286 @@
+// Tap-hold keys - send one key when tapped and act as a modifier or layer when held
+
+use crate::events::KeyEvent;
+use heapless::{Deque, Vec};
+use usbd_human_interface_device::page::Keyboard;
+
+// events that can be held back while waiting to decide tap or hold
+pub const BUFFER_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TapHoldConfig {
+    // how long a key has to be held down to count as a hold (ms)
+    pub tapping_term: u32,
+    // another key pressed and released while the tap-hold key is down counts as a hold
+    pub permissive_hold: bool,
+    // any other key pressed while the tap-hold key is down counts as a hold
+    pub hold_on_other_key_press: bool,
+    // pressing the key again within this time (ms) of tapping it holds the tap key (for key repeat), 0 to disable
+    pub quick_tap_term: u32,
+    // held past the tapping term and released without pressing another key still sends the tap
+    pub retro_tapping: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Decision {
+    Tap,
+    Hold,
+}
+
+// tap-hold key waiting for a decision
+#[derive(Clone, Copy)]
+struct Waiting {
+    row: usize,
+    col: usize,
+    timestamp: u32,
+}
+
+pub struct TapHold {
+    pub config: TapHoldConfig,
+    waiting: Option<Waiting>,
+    // events that came in while waiting, replayed once the decision is made
+    buffer: Deque<KeyEvent, BUFFER_SIZE>,
+    // last tap-hold key that was tapped (row, col, time) for quick tap
+    last_tap: Option<(usize, usize, u32)>,
+    // held tap-hold key (row, col, tap key) that will still send its tap on release if no other key is pressed
+    retro: Option<(usize, usize, Keyboard)>,
+}
+
+impl TapHold {
+    pub const fn new(config: TapHoldConfig) -> Self {
+        TapHold {
+            config,
+            waiting: None,
+            buffer: Deque::new(),
+            last_tap: None,
+            retro: None,
+        }
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.waiting.is_some()
+    }
+
+    // ? a tap-hold key was pressed - decides straight away for a quick tap, otherwise waits
+    pub fn start(&mut self, event: &KeyEvent) -> Option<Decision> {
+        if let Some((row, col, timestamp)) = self.last_tap {
+            if row == event.row
+                && col == event.col
+                && event.timestamp.wrapping_sub(timestamp) < self.config.quick_tap_term
+            {
+                return Some(Decision::Tap);
+            }
+        }
+        self.waiting = Some(Waiting {
+            row: event.row,
+            col: event.col,
+            timestamp: event.timestamp,
+        });
+        None
+    }
+
+    // ? another event while waiting - it is buffered and may decide tap or hold
+    pub fn event(&mut self, event: KeyEvent) -> Option<Decision> {
+        let waiting = self.waiting?;
+        let pressed_after = |buffer: &Deque<KeyEvent, BUFFER_SIZE>| {
+            buffer
+                .iter()
+                .any(|e| e.pressed && e.row == event.row && e.col == event.col)
+        };
+        let decision = if event.row == waiting.row && event.col == waiting.col {
+            // the tap-hold key itself was released
+            let other_pressed = self.buffer.iter().any(|e| e.pressed);
+            if event.timestamp.wrapping_sub(waiting.timestamp) < self.config.tapping_term
+                || (self.config.retro_tapping && !other_pressed)
+            {
+                self.last_tap = Some((event.row, event.col, event.timestamp));
+                Some(Decision::Tap)
+            } else {
+                Some(Decision::Hold)
+            }
+        } else if event.pressed && self.config.hold_on_other_key_press {
+            Some(Decision::Hold)
+        } else if !event.pressed && self.config.permissive_hold && pressed_after(&self.buffer) {
+            // a key pressed after the tap-hold key was also released before it
+            Some(Decision::Hold)
+        } else {
+            None
+        };
+        // buffer is always emptied on a decision so there is room for this event
+        self.buffer.push_back(event).ok();
+        if decision.is_none() && self.buffer.is_full() {
+            // too many keys held back, treat as a hold rather than dropping events
+            return Some(Decision::Hold);
+        }
+        decision
+    }
+
+    // ? check the tapping term - held long enough is a hold
+    pub fn tick(&mut self, now: u32) -> Option<Decision> {
+        let waiting = self.waiting?;
+        if now.wrapping_sub(waiting.timestamp) >= self.config.tapping_term {
+            Some(Decision::Hold)
+        } else {
+            None
+        }
+    }
+
+    // position of the tap-hold key waiting for a decision
+    pub fn waiting_key(&self) -> Option<(usize, usize)> {
+        self.waiting.map(|waiting| (waiting.row, waiting.col))
+    }
+
+    // ? finish waiting - returns the events to replay in order
+    pub fn finish(&mut self, decision: Decision, tap: Keyboard) -> Vec<KeyEvent, BUFFER_SIZE> {
+        let mut events = Vec::new();
+        if let Some(waiting) = self.waiting.take() {
+            let other_pressed = self.buffer.iter().any(|e| e.pressed);
+            if decision == Decision::Hold && self.config.retro_tapping && !other_pressed {
+                self.retro = Some((waiting.row, waiting.col, tap));
+            }
+            while let Some(event) = self.buffer.pop_front() {
+                events.push(event).ok();
+            }
+        }
+        events
+    }
+
+    // ? retro tapping - the tap key to send when a held tap-hold key is released untouched
+    pub fn retro_tap(&mut self, event: &KeyEvent) -> Option<Keyboard> {
+        let (row, col, tap) = self.retro?;
+        if event.pressed {
+            // another key was pressed so it was used as a hold
+            self.retro = None;
+            None
+        } else if row == event.row && col == event.col {
+            self.retro = None;
+            Some(tap)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: TapHoldConfig = TapHoldConfig {
+        tapping_term: 200,
+        permissive_hold: false,
+        hold_on_other_key_press: false,
+        quick_tap_term: 120,
+        retro_tapping: false,
+    };
+
+    #[test]
+    fn released_within_the_tapping_term_is_a_tap() {
+        let mut tap_hold = TapHold::new(CONFIG);
+        assert_eq!(tap_hold.start(&KeyEvent::test(2, 0, true, 0)), None);
+        assert_eq!(tap_hold.tick(199), None);
+        assert_eq!(
+            tap_hold.event(KeyEvent::test(2, 0, false, 100)),
+            Some(Decision::Tap)
+        );
+        // the release is replayed once the tap is applied
+        let replay = tap_hold.finish(Decision::Tap, Keyboard::Escape);
+        assert_eq!(replay.as_slice(), &[KeyEvent::test(2, 0, false, 100)]);
+        assert!(!tap_hold.is_waiting());
+    }
+
+    #[test]
+    fn held_to_the_tapping_term_is_a_hold() {
+        let mut tap_hold = TapHold::new(CONFIG);
+        tap_hold.start(&KeyEvent::test(2, 0, true, 1000));
+        assert_eq!(tap_hold.tick(1199), None);
+        assert_eq!(tap_hold.tick(1200), Some(Decision::Hold));
+        assert!(tap_hold.finish(Decision::Hold, Keyboard::Escape).is_empty());
+    }
+
+    #[test]
+    fn other_keys_are_held_back_until_decided() {
+        let mut tap_hold = TapHold::new(CONFIG);
+        tap_hold.start(&KeyEvent::test(2, 0, true, 0));
+        assert_eq!(tap_hold.event(KeyEvent::test(1, 1, true, 50)), None);
+        assert_eq!(tap_hold.event(KeyEvent::test(1, 1, false, 80)), None);
+        assert_eq!(
+            tap_hold.event(KeyEvent::test(2, 0, false, 90)),
+            Some(Decision::Tap)
+        );
+        let replay = tap_hold.finish(Decision::Tap, Keyboard::Escape);
+        assert_eq!(
+            replay.as_slice(),
+            &[
+                KeyEvent::test(1, 1, true, 50),
+                KeyEvent::test(1, 1, false, 80),
+                KeyEvent::test(2, 0, false, 90)
+            ]
+        );
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut tap_hold = TapHold::new(TapHoldConfig {
+            permissive_hold: true,
+            ..CONFIG
+        });
+        tap_hold.start(&KeyEvent::test(2, 0, true, 0));
+        assert_eq!(tap_hold.event(KeyEvent::test(1, 1, true, 50)), None);
+        assert_eq!(
+            tap_hold.event(KeyEvent::test(1, 1, false, 80)),
+            Some(Decision::Hold)
+        );
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut tap_hold = TapHold::new(TapHoldConfig {
+            hold_on_other_key_press: true,
+            ..CONFIG
+        });
+        tap_hold.start(&KeyEvent::test(2, 0, true, 0));
+        assert_eq!(
+            tap_hold.event(KeyEvent::test(1, 1, true, 50)),
+            Some(Decision::Hold)
+        );
+    }
+
+    #[test]
+    fn quick_tap_after_a_tap() {
+        let mut tap_hold = TapHold::new(CONFIG);
+        tap_hold.start(&KeyEvent::test(2, 0, true, 0));
+        tap_hold.event(KeyEvent::test(2, 0, false, 50));
+        tap_hold.finish(Decision::Tap, Keyboard::Escape);
+        // pressed again within the quick tap term
+        assert_eq!(
+            tap_hold.start(&KeyEvent::test(2, 0, true, 150)),
+            Some(Decision::Tap)
+        );
+        // and not after it
+        tap_hold.event(KeyEvent::test(2, 0, false, 160));
+        tap_hold.finish(Decision::Tap, Keyboard::Escape);
+        assert_eq!(tap_hold.start(&KeyEvent::test(2, 0, true, 400)), None);
+    }
+
+    #[test]
+    fn retro_tapping() {
+        let mut tap_hold = TapHold::new(TapHoldConfig {
+            retro_tapping: true,
+            ..CONFIG
+        });
+        tap_hold.start(&KeyEvent::test(2, 0, true, 0));
+        assert_eq!(tap_hold.tick(200), Some(Decision::Hold));
+        tap_hold.finish(Decision::Hold, Keyboard::Escape);
+        assert_eq!(
+            tap_hold.retro_tap(&KeyEvent::test(2, 0, false, 300)),
+            Some(Keyboard::Escape)
+        );
+
+        // not once another key has been pressed
+        tap_hold.start(&KeyEvent::test(2, 0, true, 1000));
+        tap_hold.tick(1200);
+        tap_hold.finish(Decision::Hold, Keyboard::Escape);
+        assert_eq!(tap_hold.retro_tap(&KeyEvent::test(1, 1, true, 1250)), None);
+        assert_eq!(tap_hold.retro_tap(&KeyEvent::test(2, 0, false, 1300)), None);
+    }
+}